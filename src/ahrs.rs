@@ -1,89 +1,529 @@
+use crate::alignment::{self, Alignment};
 use crate::chrono::Chrono;
+use crate::dyn_notch::{self, DynNotch};
+use crate::estimators::{self, AttitudeEstimator, Estimator};
+use crate::filters::{self, Filter};
+use crate::health;
+use crate::imu::{self, Imu, Sample};
+use crate::kalman;
+use crate::mag;
 use crate::prelude::*;
+use crate::quaternion::Quaternion;
+use crate::thermal;
+use crate::types::{CalibrationError, CalibrationStatus};
+use crate::vibration;
 
-use dcmimu::DCMIMU;
 use ehal::blocking::delay::DelayMs;
+use libm::{fabsf, sqrtf};
 
-use ehal::blocking::spi;
-use mpu9250::Mpu9250;
-
-// Magnetometer calibration parameters
-// NOTE you need to use the right parameters for *your* magnetometer
-// You can use the `log-sensors` example to calibrate your magnetometer. The
-// producer is explained in https://github.com/kriswiner/MPU6050/wiki/Simple-and-Effective-Magnetometer-Calibration
-
-// const M_BIAS_X: f32 = 150.;
-// const M_SCALE_X: f32 = 0.9;
-// const M_BIAS_Y: f32 = -60.;
-// const M_SCALE_Y: f32 = 0.9;
-// const M_BIAS_Z: f32 = -220.;
-// const M_SCALE_Z: f32 = 1.2;
-
-// TODO: make generic over Imu/Marg
-pub struct AHRS<DEV, T> {
-    mpu: Mpu9250<DEV, mpu9250::Imu>,
-    dcmimu: DCMIMU,
-    //     accel_biases: Vector3<f32>,
+// smoothing of measured loop rate
+const LOOP_RATE_K: f32 = 0.05;
+
+// Calibration at rest
+const CALIBRATION_SAMPLES: usize = 256;
+const CALIBRATION_ATTEMPTS: usize = 5;
+// rad/s, gyro noise std above this means we've been moved
+const MAX_REST_GYRO_STD: f32 = 0.02;
+// m/s^2
+const MAX_REST_ACCEL_STD: f32 = 0.3;
+// relative deviation of accel norm from G
+const MAX_GRAVITY_ERROR: f32 = 0.15;
+// off-axis gravity at rest relative to G, about 6 degrees of tilt; it
+// can't be told from accel bias
+const MAX_TILT: f32 = 0.1;
+
+pub struct AHRS<S, T> {
+    imu: S,
+    imu_config: imu::Config,
+    // WHO_AM_I at start
+    imu_id: u8,
+    health: health::Monitor,
+    sample_rate_hz: f32,
+    alignment: alignment::Rotation,
+    estimator: Estimator,
+    kalman: kalman::RollPitch,
+    accel_biases: [f32; 3],
+    gyro_biases: [f32; 3],
+    // die temperature when biases were taken
+    rest_temp_c: f32,
+    // biases were accepted at least once
+    rest_calibrated: bool,
+    rest_calibration: CalibrationStatus,
+    // Some while biases are taken again
+    rest_calibrator: Option<RestCalibrator>,
+    gyro_temp: thermal::Model,
+    // Some while gyro temperature calibration runs
+    temp_calibrator: Option<thermal::Calibrator>,
     timer_ms: T,
+    dyn_notch: DynNotch,
+    gyro_notch: filters::Vector<filters::Notch>,
+    gyro_lpf: filters::Vector<filters::LowPass>,
+    accel_lpf: filters::Vector<filters::LowPass>,
+    vibration: vibration::Meter,
+    mag_calibration: mag::Calibration,
+    // Some while calibration routine runs
+    mag_calibrator: Option<mag::Calibrator>,
+    heading: mag::HeadingFusion,
+    // returned again when no new samples arrived
+    last: AhrsResult,
+    overflows: u32,
+    // since last step with new samples
+    unprocessed_s: f32,
+    loop_hz: f32,
 }
 
-impl<DEV, E, T> AHRS<DEV, T>
+impl<S, E, T> AHRS<S, T>
 where
-    DEV: mpu9250::Device<Error = E>,
+    S: Imu<Error = E>,
     T: Chrono,
 {
+    // `imu` is already configured with `imu_config`. Fails on bus errors
+    // only: rejected calibration at rest leaves biases at zero and
+    // `calibrated` false until `calibrate_at_rest` succeeds.
     pub fn create<D>(
-        mut mpu: Mpu9250<DEV, mpu9250::Imu>,
+        mut imu: S,
+        imu_config: &imu::Config,
         delay: &mut D,
         timer_ms: T,
-    ) -> Self
+    ) -> Result<Self, E>
     where
         D: DelayMs<u8>,
     {
-        // let mut accel_biases = mpu.calibrate_at_rest(delay)?;
-        // Accel biases contain compensation for Earth gravity,
-        // so when we will adjust measurements with those biases, gravity will
-        // be cancelled. This is helpful for some algos, but not the
-        // others. For DCMIMU we need gravity, so we will add it back
-        // to measurements, by adjusting biases once.
-        // TODO: find real Z axis.
-        // accel_biases.z -= mpu9250::G;
-        let dcmimu = DCMIMU::new();
-        AHRS {
-            mpu,
-            dcmimu,
-            // accel_biases,
+        let result = calibrate_at_rest(&mut imu, delay)?;
+        let (accel_biases, gyro_biases, rest_temp_c) =
+            result.unwrap_or(([0.0; 3], [0.0; 3], 0.0));
+        Ok(AHRS {
+            sample_rate_hz: imu.sample_rate_hz(),
+            imu_id: imu.who_am_i()?,
+            imu,
+            imu_config: *imu_config,
+            health: health::Monitor::new(imu_config),
+            alignment: alignment::Rotation::new(),
+            estimator: Estimator::new(&estimators::Config::new()),
+            kalman: kalman::RollPitch::new(),
+            accel_biases,
+            gyro_biases,
+            rest_temp_c,
+            rest_calibrated: result.is_ok(),
+            rest_calibration: CalibrationStatus::from_result(&result),
+            rest_calibrator: None,
+            gyro_temp: thermal::Model::new(),
+            temp_calibrator: None,
             timer_ms,
+            dyn_notch: DynNotch::new(),
+            gyro_notch: filters::Vector::notch(),
+            gyro_lpf: filters::Vector::lowpass(),
+            accel_lpf: filters::Vector::lowpass(),
+            vibration: vibration::Meter::new(imu_config),
+            mag_calibration: mag::Calibration::new(),
+            mag_calibrator: None,
+            heading: mag::HeadingFusion::new(),
+            last: AhrsResult::new(),
+            overflows: 0,
+            unprocessed_s: 0.,
+            loop_hz: 0.,
+        })
+    }
+
+    // Cheap if nothing changed; filters follow new sample rate on
+    // their next `configure_filters`.
+    pub fn configure_imu(&mut self, config: &imu::Config) -> Result<(), E> {
+        if self.imu_config == *config {
+            return Ok(());
+        }
+        if let Err(e) = self.imu.configure(config) {
+            self.health.bus_error();
+            return Err(e);
+        }
+        self.imu_config = *config;
+        self.sample_rate_hz = self.imu.sample_rate_hz();
+        self.health.configure_range(config);
+        self.vibration.configure_range(config);
+        Ok(())
+    }
+
+    pub fn configure_filters(&mut self, settings: &filters::Settings) {
+        let rate = self.sample_rate_hz;
+        self.dyn_notch.configure(&settings.dyn_notch, rate);
+        self.gyro_notch.configure(&settings.notch, rate);
+        self.gyro_lpf.configure(&settings.gyro, rate);
+        self.accel_lpf.configure(&settings.accel, rate);
+        self.vibration.configure_rate(rate);
+    }
+
+    #[inline]
+    pub fn configure_vibration(&mut self, config: &vibration::Config) {
+        self.vibration.configure(config);
+    }
+
+    #[inline]
+    pub fn configure_alignment(&mut self, alignment: &Alignment) {
+        self.alignment.configure(alignment);
+    }
+
+    #[inline]
+    pub fn configure_estimator(&mut self, config: &estimators::Config) {
+        self.estimator.configure(config);
+        self.kalman.configure(&config.kalman);
+    }
+
+    #[inline]
+    pub fn configure_mag(&mut self, calibration: &mag::Calibration) {
+        self.mag_calibration = *calibration;
+    }
+
+    // Starts collecting when `active` is set, finishes when it's cleared;
+    // returns outcome of the run when finished.
+    pub fn calibrate_mag(
+        &mut self,
+        active: bool,
+    ) -> Option<Result<mag::Calibration, CalibrationError>> {
+        match (active, self.mag_calibrator.as_ref()) {
+            (true, None) => {
+                self.mag_calibrator = Some(mag::Calibrator::new());
+                None
+            }
+            (false, Some(calibrator)) => {
+                let result = calibrator.finish();
+                self.mag_calibrator = None;
+                Some(result)
+            }
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn vibration(&self) -> vibration::Levels {
+        self.vibration.levels()
+    }
+
+    #[inline]
+    pub fn configure_gyro_temp(&mut self, model: &thermal::Model) {
+        self.gyro_temp = *model;
+    }
+
+    // Same as `calibrate_mag`, board has to stay at rest meanwhile
+    pub fn calibrate_gyro_temp(
+        &mut self,
+        active: bool,
+    ) -> Option<Result<thermal::Model, CalibrationError>> {
+        match (active, self.temp_calibrator.as_ref()) {
+            (true, None) => {
+                self.temp_calibrator = Some(thermal::Calibrator::new());
+                None
+            }
+            (false, Some(calibrator)) => {
+                let result = calibrator.finish();
+                self.temp_calibrator = None;
+                Some(result)
+            }
+            _ => None,
         }
     }
 
-    pub fn setup_time(&mut self) {
+    // Same as calibration at start, from the sample stream: starts when
+    // `requested` is set, returns outcome once enough samples were taken.
+    // Rejected run keeps biases of the last accepted one.
+    pub fn calibrate_at_rest(
+        &mut self,
+        requested: bool,
+    ) -> Option<Result<(), CalibrationError>> {
+        let result = match self.rest_calibrator.as_ref() {
+            None if requested => {
+                self.rest_calibrator = Some(RestCalibrator::new());
+                return None;
+            }
+            Some(calibrator) if calibrator.done() => calibrator.finish(),
+            _ => return None,
+        };
+        self.rest_calibrator = None;
+        self.rest_calibration = CalibrationStatus::from_result(&result);
+        Some(result.map(|(accel, gyro, temp_c)| {
+            self.accel_biases = accel;
+            self.gyro_biases = gyro;
+            self.rest_temp_c = temp_c;
+            self.rest_calibrated = true;
+        }))
+    }
+
+    // biases are known and not being taken, safe to spin motors
+    #[inline]
+    pub fn calibrated(&self) -> bool {
+        self.rest_calibrated && self.rest_calibrator.is_none()
+    }
+
+    #[inline]
+    pub fn rest_calibration(&self) -> CalibrationStatus {
+        self.rest_calibration
+    }
+
+    // tracked vibration peaks per axis, Hz
+    #[inline]
+    pub fn notch_peaks(&self) -> [[f32; dyn_notch::MAX_PEAKS]; 3] {
+        self.dyn_notch.peaks()
+    }
+
+    pub fn setup_time(&mut self) -> Result<(), E> {
+        self.imu.start()?;
         self.timer_ms.reset();
+        Ok(())
     }
 
+    // Processes every sample acquired since previous call; result is
+    // the latest one with `dt_s` covering all of them.
     pub fn estimate(&mut self) -> Result<AhrsResult, E> {
-        let meas = self.mpu.all::<[f32; 3]>()?;
-        let dt_s = self.timer_ms.split_time_s();
-        let accel = meas.accel;
-        let gyro = meas.gyro;
-        let (ypr, gyro_biases) = self.dcmimu.update(
-            (gyro[0], gyro[1], gyro[2]),
-            (accel[0], accel[1], accel[2]),
-            dt_s,
-        );
-        let biased_gyro = [
-            gyro[0] - gyro_biases.x,
-            gyro[1] - gyro_biases.y,
-            gyro[2] - gyro_biases.z,
-        ];
-        Ok(AhrsResult {
+        let result = self.acquire();
+        if result.is_err() {
+            self.health.bus_error();
+        }
+        result
+    }
+
+    // Once per step, after `estimate`: checks identity when it's due
+    // and re-initializes sensor if anything failed.
+    pub fn supervise(&mut self, config: &health::Config) -> health::Counters {
+        if self.health.check_due(config) {
+            match self.imu.who_am_i() {
+                Ok(id) if id == self.imu_id => {}
+                Ok(_) => self.health.bad_id(),
+                Err(_) => self.health.bus_error(),
+            }
+        }
+        if self.health.failing() {
+            self.health.reinit();
+            if self.imu.recover().is_err() {
+                self.health.bus_error();
+            }
+        }
+        self.health.finish(config)
+    }
+
+    #[inline]
+    pub fn clear_failsafe(&mut self) {
+        self.health.clear_failsafe();
+    }
+
+    fn acquire(&mut self) -> Result<AhrsResult, E> {
+        let pending = self.imu.pending()?;
+        let elapsed_s = self.timer_ms.split_time_s();
+        if pending.overflow {
+            self.overflows = self.overflows.wrapping_add(1);
+        }
+        let dt_s = pending.period_s.unwrap_or(elapsed_s);
+        self.unprocessed_s += elapsed_s;
+        if pending.count > 0 && self.unprocessed_s > 0. {
+            let hz = 1. / self.unprocessed_s;
+            self.loop_hz += LOOP_RATE_K * (hz - self.loop_hz);
+            self.unprocessed_s = 0.;
+        }
+        let mut result = self.last;
+        for _ in 0..pending.count {
+            let meas = self.imu.next_sample()?;
+            self.health.check(&meas);
+            result = self.process(&meas, dt_s);
+        }
+        result.dt_s = dt_s * pending.count as f32;
+        result.samples = pending.count;
+        result.overflows = self.overflows;
+        result.loop_hz = self.loop_hz;
+        self.last = result;
+        Ok(result)
+    }
+
+    fn process(&mut self, meas: &Sample, dt_s: f32) -> AhrsResult {
+        if let Some(ref mut calibrator) = self.rest_calibrator {
+            calibrator.add(meas);
+        }
+        if let Some(ref mut calibrator) = self.temp_calibrator {
+            calibrator.add(&meas.gyro, meas.temp_c);
+        }
+        // biases, their drift and mag calibration are in sensor frame
+        let drift = self.gyro_temp.drift(meas.temp_c, self.rest_temp_c);
+        let gyro = sub(&sub(&meas.gyro, &self.gyro_biases), &drift);
+        let accel = self.alignment.apply(&sub(&meas.accel, &self.accel_biases));
+        let gyro = self.alignment.apply(&gyro);
+        let trust = self.vibration.update(&meas.accel, &accel);
+        self.estimator.set_accel_trust(trust);
+        let mut field = None;
+        if let Some(raw) = meas.mag {
+            match self.mag_calibrator {
+                Some(ref mut calibrator) => calibrator.add(&raw),
+                None => {
+                    let calibrated = self.mag_calibration.apply(&raw);
+                    field = Some(self.alignment.apply(&calibrated));
+                }
+            }
+        }
+        let attitude =
+            self.estimator.update(&gyro, &accel, field.as_ref(), dt_s);
+        let mut ypr = attitude.ypr;
+        let mut q = attitude.q;
+        if let Some(ref field) = field {
+            if !self.estimator.uses_mag() {
+                let heading = mag::heading(field, ypr.roll, ypr.pitch);
+                ypr.yaw = self.heading.fuse(ypr.yaw, heading, dt_s);
+                q = Quaternion::from_euler(ypr.yaw, ypr.pitch, ypr.roll);
+            }
+        }
+        let estimator_rp = [ypr.roll, ypr.pitch];
+        let kalman_rp = self.kalman.update(&accel, &gyro, dt_s);
+        match self.kalman.overrides() {
+            (false, false) => {}
+            (roll, pitch) => {
+                if roll {
+                    ypr.roll = kalman_rp[0];
+                }
+                if pitch {
+                    ypr.pitch = kalman_rp[1];
+                }
+                q = Quaternion::from_euler(ypr.yaw, ypr.pitch, ypr.roll);
+            }
+        }
+        // estimator gets raw samples, controllers get filtered
+        let accel = self.accel_lpf.apply(&accel);
+        let gyro = self.dyn_notch.apply(&gyro);
+        let gyro = self.gyro_notch.apply(&gyro);
+        let gyro = self.gyro_lpf.apply(&gyro);
+        let biased_gyro = sub(&gyro, &attitude.gyro_biases);
+        AhrsResult {
+            q,
             ypr,
             accel,
             gyro,
             biased_gyro,
+            mag: field.unwrap_or([0.0; 3]),
+            estimator_rp,
+            kalman_rp,
+            variances: self.estimator.variances(),
             dt_s,
-        })
+            temp_c: meas.temp_c,
+            samples: 1,
+            overflows: self.overflows,
+            sample_rate_hz: self.sample_rate_hz,
+            loop_hz: self.loop_hz,
+        }
+    }
+}
+
+// Averages gyro and accel at rest; rejects attempts with motion.
+// Returns (accel, gyro) biases and temperature they were taken at.
+// Outer result is bus error, inner one is outcome of the last attempt
+fn calibrate_at_rest<S, E, D>(
+    imu: &mut S,
+    delay: &mut D,
+) -> Result<Result<RestBiases, CalibrationError>, E>
+where
+    S: Imu<Error = E>,
+    D: DelayMs<u8>,
+{
+    let sample_ms = (1000. / imu.sample_rate_hz()) as u8;
+    let mut result = Err(CalibrationError::Moved);
+    for _ in 0..CALIBRATION_ATTEMPTS {
+        let mut calibrator = RestCalibrator::new();
+        while !calibrator.done() {
+            calibrator.add(&imu.sample()?);
+            delay.delay_ms(sample_ms);
+        }
+        result = calibrator.finish();
+        if result != Err(CalibrationError::Moved) {
+            break;
+        }
+    }
+    Ok(result)
+}
+
+// accel and gyro biases, die temperature
+type RestBiases = ([f32; 3], [f32; 3], f32);
+
+// Mean and variance of raw readings at rest
+struct RestCalibrator {
+    samples: usize,
+    accel_sum: [f32; 3],
+    accel_sq: [f32; 3],
+    gyro_sum: [f32; 3],
+    gyro_sq: [f32; 3],
+    temp_sum: f32,
+}
+
+impl RestCalibrator {
+    #[inline]
+    const fn new() -> Self {
+        RestCalibrator {
+            samples: 0,
+            accel_sum: [0.0; 3],
+            accel_sq: [0.0; 3],
+            gyro_sum: [0.0; 3],
+            gyro_sq: [0.0; 3],
+            temp_sum: 0.0,
+        }
+    }
+
+    #[inline]
+    fn done(&self) -> bool {
+        self.samples >= CALIBRATION_SAMPLES
+    }
+
+    fn add(&mut self, meas: &Sample) {
+        if self.done() {
+            return;
+        }
+        self.samples += 1;
+        self.temp_sum += meas.temp_c;
+        for i in 0..3 {
+            self.accel_sum[i] += meas.accel[i];
+            self.accel_sq[i] += meas.accel[i] * meas.accel[i];
+            self.gyro_sum[i] += meas.gyro[i];
+            self.gyro_sq[i] += meas.gyro[i] * meas.gyro[i];
+        }
     }
+
+    fn finish(&self) -> Result<RestBiases, CalibrationError> {
+        if !self.done() {
+            return Err(CalibrationError::TooFewSamples);
+        }
+        let n = self.samples as f32;
+        let mut accel = [0.0; 3];
+        let mut gyro = [0.0; 3];
+        let mut moved = false;
+        for i in 0..3 {
+            accel[i] = self.accel_sum[i] / n;
+            gyro[i] = self.gyro_sum[i] / n;
+            let accel_var = self.accel_sq[i] / n - accel[i] * accel[i];
+            let gyro_var = self.gyro_sq[i] / n - gyro[i] * gyro[i];
+            moved |= accel_var > MAX_REST_ACCEL_STD * MAX_REST_ACCEL_STD
+                || gyro_var > MAX_REST_GYRO_STD * MAX_REST_GYRO_STD;
+        }
+        if moved {
+            return Err(CalibrationError::Moved);
+        }
+
+        let norm = sqrtf(
+            accel[0] * accel[0] + accel[1] * accel[1] + accel[2] * accel[2],
+        );
+        if fabsf(norm - imu::G) > MAX_GRAVITY_ERROR * imu::G {
+            return Err(CalibrationError::BadGravity);
+        }
+        // Averaged accel contains Earth gravity as well, but estimators
+        // need it, so leave it along the axis that looks down. Whatever
+        // is left on the other axes would become bias.
+        let mut down = 0;
+        for i in 1..3 {
+            if fabsf(accel[i]) > fabsf(accel[down]) {
+                down = i;
+            }
+        }
+        let off_axis = sqrtf(norm * norm - accel[down] * accel[down]);
+        if off_axis > MAX_TILT * imu::G {
+            return Err(CalibrationError::Tilted);
+        }
+        accel[down] -= if accel[down] > 0. { imu::G } else { -imu::G };
+        Ok((accel, gyro, self.temp_sum / n))
+    }
+}
+
+#[inline]
+fn sub(a: &[f32; 3], b: &[f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -91,8 +531,28 @@ pub struct AhrsResult {
     pub accel: [f32; 3],
     pub gyro: [f32; 3],
     pub dt_s: f32,
+    // body to earth
+    pub q: Quaternion,
+    // same attitude, for logs and telemetry
     pub ypr: dcmimu::EulerAngles,
     pub biased_gyro: [f32; 3],
+    // calibrated, zero without magnetometer
+    pub mag: [f32; 3],
+    // roll and pitch from attitude estimator and from per-axis Kalman
+    pub estimator_rp: [f32; 2],
+    pub kalman_rp: [f32; 2],
+    // estimator covariance diagonal: angle, gyro bias, earth field
+    pub variances: [f32; estimators::ekf::MAX_STATES],
+    // gyro die temperature, degrees Celsius
+    pub temp_c: f32,
+    // processed in this step, zero when nothing new arrived
+    pub samples: usize,
+    // acquisition overflows since start
+    pub overflows: u32,
+    // actual sensor rate
+    pub sample_rate_hz: f32,
+    // measured rate of steps with new samples, smoothed
+    pub loop_hz: f32,
 }
 
 impl AhrsResult {
@@ -102,12 +562,22 @@ impl AhrsResult {
             accel: [0.0, 0.0, 0.0],
             gyro: [0.0, 0.0, 0.0],
             dt_s: 0.0,
+            q: Quaternion::identity(),
             ypr: EulerAngles {
                 yaw: 0.0,
                 pitch: 0.0,
                 roll: 0.0,
             },
             biased_gyro: [0.0, 0.0, 0.0],
+            mag: [0.0, 0.0, 0.0],
+            estimator_rp: [0.0, 0.0],
+            kalman_rp: [0.0, 0.0],
+            variances: [0.0; estimators::ekf::MAX_STATES],
+            temp_c: 0.0,
+            samples: 0,
+            overflows: 0,
+            sample_rate_hz: 0.0,
+            loop_hz: 0.0,
         }
     }
 
@@ -148,3 +618,63 @@ impl AhrsResult {
         ]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at_rest(accel: [f32; 3]) -> RestCalibrator {
+        let mut calibrator = RestCalibrator::new();
+        for i in 0..CALIBRATION_SAMPLES {
+            let noise = if i % 2 == 0 { 0.01 } else { -0.01 };
+            calibrator.add(&Sample {
+                accel: [accel[0] + noise, accel[1], accel[2]],
+                gyro: [0.01, -0.02, noise],
+                mag: None,
+                temp_c: 30.,
+            });
+        }
+        calibrator
+    }
+
+    #[test]
+    fn level_board_leaves_bias_only() {
+        let (accel, gyro, temp_c) =
+            at_rest([0.2, -0.1, -imu::G + 0.3]).finish().ok().unwrap();
+        for (bias, expected) in accel.iter().zip(&[0.2, -0.1, 0.3]) {
+            assert!(fabsf(bias - expected) < 1e-3);
+        }
+        assert!(fabsf(gyro[1] + 0.02) < 1e-4);
+        assert_eq!(temp_c, 30.);
+    }
+
+    #[test]
+    fn tilt_is_not_taken_for_bias() {
+        // 10 degrees about pitch
+        let accel = [imu::G * 0.1736, 0., -imu::G * 0.9848];
+        let result = at_rest(accel).finish();
+        assert!(result == Err(CalibrationError::Tilted));
+    }
+
+    #[test]
+    fn motion_is_rejected() {
+        let mut calibrator = at_rest([0., 0., imu::G]);
+        calibrator.gyro_sq[2] += CALIBRATION_SAMPLES as f32;
+        let result = calibrator.finish();
+        assert!(result == Err(CalibrationError::Moved));
+    }
+
+    #[test]
+    fn too_few_samples_are_not_finished() {
+        let mut calibrator = RestCalibrator::new();
+        calibrator.add(&Sample {
+            accel: [0., 0., imu::G],
+            gyro: [0.; 3],
+            mag: None,
+            temp_c: 30.,
+        });
+        assert!(!calibrator.done());
+        let result = calibrator.finish();
+        assert!(result == Err(CalibrationError::TooFewSamples));
+    }
+}
@@ -0,0 +1,108 @@
+// Sensor to body frame rotation: mounting preset and fine trim.
+use crate::quaternion::Quaternion;
+use crate::utils::to_rads;
+
+// Sensor rotated clockwise around body z, looking from the top;
+// flipped ones are mounted upside down (rotated around y).
+#[derive(Copy, Clone, PartialEq)]
+pub enum Preset {
+    Cw0,
+    Cw90,
+    Cw180,
+    Cw270,
+    Cw0Flip,
+    Cw90Flip,
+    Cw180Flip,
+    Cw270Flip,
+}
+
+impl Preset {
+    #[inline]
+    pub fn from_i32(v: i32) -> Option<Self> {
+        match v {
+            0 => Some(Preset::Cw0),
+            1 => Some(Preset::Cw90),
+            2 => Some(Preset::Cw180),
+            3 => Some(Preset::Cw270),
+            4 => Some(Preset::Cw0Flip),
+            5 => Some(Preset::Cw90Flip),
+            6 => Some(Preset::Cw180Flip),
+            7 => Some(Preset::Cw270Flip),
+            _ => None,
+        }
+    }
+
+    // body from sensor axes
+    fn matrix(&self) -> [[f32; 3]; 3] {
+        match self {
+            Preset::Cw0 => [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]],
+            Preset::Cw90 => [[0., 1., 0.], [-1., 0., 0.], [0., 0., 1.]],
+            Preset::Cw180 => [[-1., 0., 0.], [0., -1., 0.], [0., 0., 1.]],
+            Preset::Cw270 => [[0., -1., 0.], [1., 0., 0.], [0., 0., 1.]],
+            Preset::Cw0Flip => [[-1., 0., 0.], [0., 1., 0.], [0., 0., -1.]],
+            Preset::Cw90Flip => [[0., 1., 0.], [1., 0., 0.], [0., 0., -1.]],
+            Preset::Cw180Flip => [[1., 0., 0.], [0., -1., 0.], [0., 0., -1.]],
+            Preset::Cw270Flip => [[0., -1., 0.], [-1., 0., 0.], [0., 0., -1.]],
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Alignment {
+    pub preset: Preset,
+    // small mounting error on top of preset: roll, pitch, yaw
+    pub trim_degrees: [f32; 3],
+}
+
+impl Alignment {
+    #[inline]
+    pub const fn new(preset: Preset) -> Self {
+        Alignment {
+            preset,
+            trim_degrees: [0.0, 0.0, 0.0],
+        }
+    }
+}
+
+pub struct Rotation {
+    alignment: Alignment,
+    m: [[f32; 3]; 3],
+}
+
+impl Rotation {
+    #[inline]
+    pub const fn new() -> Self {
+        Rotation {
+            alignment: Alignment::new(Preset::Cw0),
+            m: [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]],
+        }
+    }
+
+    // cheap if nothing changed
+    pub fn configure(&mut self, alignment: &Alignment) {
+        if self.alignment == *alignment {
+            return;
+        }
+        self.alignment = *alignment;
+        let [roll, pitch, yaw] = alignment.trim_degrees;
+        let trim =
+            Quaternion::from_euler(to_rads(yaw), to_rads(pitch), to_rads(roll))
+                .to_matrix();
+        let preset = alignment.preset.matrix();
+        for i in 0..3 {
+            for j in 0..3 {
+                self.m[i][j] = (0..3).map(|k| trim[i][k] * preset[k][j]).sum();
+            }
+        }
+    }
+
+    #[inline]
+    pub fn apply(&self, v: &[f32; 3]) -> [f32; 3] {
+        let m = &self.m;
+        [
+            m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2],
+            m[1][0] * v[0] + m[1][1] * v[1] + m[1][2] * v[2],
+            m[2][0] * v[0] + m[2][1] * v[1] + m[2][2] * v[2],
+        ]
+    }
+}
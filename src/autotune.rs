@@ -0,0 +1,268 @@
+// Relay feedback (Åström–Hägglund) autotune of the rate loop:
+// one axis is driven by bang-bang output on its rate error, the rest
+// keep hovering; ultimate gain and period of resulting limit cycle
+// give PID gains.
+use core::f32::consts::PI;
+
+use crate::types::Gains;
+
+use libm::sqrtf;
+
+// first cycles are transient
+const SKIP_CYCLES: usize = 2;
+const MEASURE_CYCLES: usize = 6;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Rule {
+    ZieglerNichols,
+    TyreusLuyben,
+}
+
+impl Rule {
+    #[inline]
+    pub fn from_i32(v: i32) -> Option<Self> {
+        match v {
+            0 => Some(Rule::ZieglerNichols),
+            1 => Some(Rule::TyreusLuyben),
+            _ => None,
+        }
+    }
+
+    // from ultimate gain and period
+    pub fn gains(&self, ku: f32, tu: f32) -> Gains {
+        let (p, ti, td) = match self {
+            Rule::ZieglerNichols => (0.6 * ku, tu / 2., tu / 8.),
+            Rule::TyreusLuyben => (ku / 2.2, 2.2 * tu, tu / 6.3),
+        };
+        Gains {
+            p,
+            i: p / ti,
+            d: p * td,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Config {
+    // body axis under test, None when autotune is off
+    pub axis: Option<usize>,
+    // relay output, duty units
+    pub amplitude: f32,
+    // rad/s
+    pub hysteresis: f32,
+    pub rule: Rule,
+}
+
+impl Config {
+    #[inline]
+    pub const fn new() -> Self {
+        Config {
+            axis: None,
+            amplitude: 100.,
+            hysteresis: 0.05,
+            rule: Rule::ZieglerNichols,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Report {
+    pub axis: usize,
+    pub cycles: usize,
+    pub done: bool,
+    pub ku: f32,
+    pub tu: f32,
+    pub gains: Gains,
+}
+
+impl Report {
+    #[inline]
+    pub const fn new() -> Self {
+        Report {
+            axis: 0,
+            cycles: 0,
+            done: false,
+            ku: 0.0,
+            tu: 0.0,
+            gains: Gains::new(),
+        }
+    }
+
+    // axis,cycles,done,ku,tu,p,i,d
+    #[inline]
+    pub fn results(&self) -> [f32; 8] {
+        [
+            self.axis as f32,
+            self.cycles as f32,
+            self.done as u8 as f32,
+            self.ku,
+            self.tu,
+            self.gains.p,
+            self.gains.i,
+            self.gains.d,
+        ]
+    }
+}
+
+pub const fn create() -> Relay {
+    Relay::new()
+}
+
+pub struct Relay {
+    config: Config,
+    output: f32,
+    // since last rising switch, s
+    elapsed: f32,
+    max: f32,
+    min: f32,
+    period_sum: f32,
+    amplitude_sum: f32,
+    report: Report,
+}
+
+impl Relay {
+    #[inline]
+    pub const fn new() -> Self {
+        Relay {
+            config: Config::new(),
+            output: 0.0,
+            elapsed: 0.0,
+            max: 0.0,
+            min: 0.0,
+            period_sum: 0.0,
+            amplitude_sum: 0.0,
+            report: Report::new(),
+        }
+    }
+
+    // restarts experiment if anything changed
+    pub fn configure(&mut self, config: &Config) {
+        if self.config == *config {
+            return;
+        }
+        let report = self.report;
+        *self = Relay::new();
+        self.config = *config;
+        // keep last results around until new ones are ready
+        self.report = report;
+        if let Some(axis) = config.axis {
+            self.report = Report::new();
+            self.report.axis = axis;
+        }
+    }
+
+    // starts experiment over, same config or not
+    pub fn restart(&mut self) {
+        let config = self.config;
+        *self = Relay::new();
+        self.config = config;
+        if let Some(axis) = config.axis {
+            self.report.axis = axis;
+        }
+    }
+
+    #[inline]
+    pub fn report(&self) -> Report {
+        self.report
+    }
+
+    // Rate error and measured rate of axis under test; returns
+    // correction, zero once done.
+    pub fn update(&mut self, error: f32, rate: f32, dt: f32) -> f32 {
+        let d = self.config.amplitude;
+        if self.report.done {
+            return 0.;
+        }
+
+        self.elapsed += dt;
+        self.max = self.max.max(rate);
+        self.min = self.min.min(rate);
+
+        let h = self.config.hysteresis;
+        if self.output == 0. {
+            // kick off
+            self.output = d;
+        } else if error > h && self.output < 0. {
+            // rising switch closes a cycle
+            self.cycle();
+            self.output = d;
+        } else if error < -h && self.output > 0. {
+            self.output = -d;
+        }
+        self.output
+    }
+
+    fn cycle(&mut self) {
+        let cycles = self.report.cycles;
+        if cycles >= SKIP_CYCLES {
+            self.period_sum += self.elapsed;
+            self.amplitude_sum += (self.max - self.min) / 2.;
+        }
+        self.report.cycles = cycles + 1;
+        self.elapsed = 0.;
+        self.max = 0.;
+        self.min = 0.;
+
+        if self.report.cycles >= SKIP_CYCLES + MEASURE_CYCLES {
+            let n = MEASURE_CYCLES as f32;
+            let tu = self.period_sum / n;
+            let a = self.amplitude_sum / n;
+            // Relay with hysteresis switches a bit late, so only the
+            // part of oscillation beyond the band counts. Oscillation
+            // inside the band is noise: ku stays zero, run is rejected.
+            let h = self.config.hysteresis;
+            if a > h {
+                let ku =
+                    4. * self.config.amplitude / (PI * sqrtf(a * a - h * h));
+                self.report.ku = ku;
+                self.report.tu = tu;
+                self.report.gains = self.config.rule.gains(ku, tu);
+            }
+            self.report.done = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libm::{fabsf, sinf};
+
+    fn tuning(hysteresis: f32) -> Relay {
+        let mut relay = Relay::new();
+        relay.configure(&Config {
+            axis: Some(0),
+            hysteresis,
+            ..Config::new()
+        });
+        relay
+    }
+
+    #[test]
+    fn hysteresis_is_taken_out_of_amplitude() {
+        let (h, a, period, dt) = (0.5, 1., 0.1, 0.0001);
+        let mut relay = tuning(h);
+        let mut t = 0.;
+        while !relay.report().done {
+            let rate = a * sinf(2. * PI * t / period);
+            relay.update(-rate, rate, dt);
+            t += dt;
+        }
+        let report = relay.report();
+        let ku = 4. * 100. / (PI * sqrtf(a * a - h * h));
+        assert!(fabsf(report.ku - ku) < ku * 0.01);
+        assert!(fabsf(report.tu - period) < period * 0.01);
+    }
+
+    #[test]
+    fn oscillation_within_hysteresis_is_rejected() {
+        let mut relay = tuning(0.5);
+        relay.report.cycles = SKIP_CYCLES + MEASURE_CYCLES - 1;
+        relay.period_sum = 0.6;
+        relay.amplitude_sum = 0.4 * MEASURE_CYCLES as f32;
+        relay.cycle();
+        let report = relay.report();
+        assert!(report.done);
+        assert_eq!(report.ku, 0.);
+    }
+}
@@ -1,5 +1,7 @@
 pub use crate::prelude::*;
 
+use crate::alignment::{self, Alignment};
+
 pub struct BoardConfiguration<
     DPT,
     SPI,
@@ -61,6 +63,10 @@ mod defs {
     pub use super::Peripherals;
     use super::*;
 
+    // Motor positions in `setup_motors` were laid out in axes of the
+    // on-board MPU9250, so they are this frame's body axes.
+    pub const ALIGNMENT: Alignment = Alignment::new(alignment::Preset::Cw0);
+
     pub type DebugPinDef<A, B> = gpio::PC15<A, B>;
     type DT = DebugPinDef<PullNone, Input>;
 
@@ -76,6 +82,9 @@ mod defs {
     pub type RxUsart = Rx<USART>;
     pub type TxCh = hal::dma::dma1::C7;
     pub type ExtiNum = hal::exti::EXTI13;
+    // bound to `ExtiNum`, pended when sensor stops signalling data ready
+    pub const IMU_INTERRUPT: mydevice::Interrupt =
+        mydevice::Interrupt::EXTI15_10;
     pub type MotorPins = (
         gpio::PA0<PullNone, gpio::Input>,
         gpio::PA1<PullNone, gpio::Input>,
@@ -202,6 +211,11 @@ mod defs {
 mod defs {
     use super::*;
 
+    // Nucleo-32 has no sensor: MPU9250 breakout is wired by hand and has
+    // no fixed mounting, so its own axes are body ones. Once it is fixed
+    // to something, orientation is set with `al=` and trims.
+    pub const ALIGNMENT: Alignment = Alignment::new(alignment::Preset::Cw0);
+
     pub type DebugPinDef<A, B> = gpio::PA11<A, B>;
     type DT = DebugPinDef<PullNone, Input>;
 
@@ -217,6 +231,8 @@ mod defs {
     pub type RxUsart = Rx<USART>;
     pub type TxCh = hal::dma::dma1::C7;
     pub type ExtiNum = hal::exti::EXTI0;
+    // bound to `ExtiNum`, pended when sensor stops signalling data ready
+    pub const IMU_INTERRUPT: mydevice::Interrupt = mydevice::Interrupt::EXTI0;
     pub type MotorPins = ();
     pub type MotorAux = ();
 
@@ -284,7 +300,21 @@ pub type SpiPins = (
 pub type SPI = Spi<SpiT, SpiPins>;
 pub type NcsPinT = NcsPinDef<Output<PushPull, HighSpeed>>;
 pub type Dev = mpu9250::SpiDevice<SPI, NcsPinT>;
-pub type MPU9250 = mpu9250::Mpu9250<Dev, mpu9250::Imu>;
+#[cfg(all(acquisition = "acquisition_single", sensors = "sensors_imu"))]
+pub type ImuT = crate::imu::mpu::Mpu<Dev, mpu9250::Imu>;
+#[cfg(all(acquisition = "acquisition_single", sensors = "sensors_marg"))]
+pub type ImuT = crate::imu::mpu::Mpu<Dev, mpu9250::Marg>;
+#[cfg(all(
+    acquisition = "acquisition_fifo",
+    any(imu = "imu_mpu9250", imu = "imu_icm20602")
+))]
+pub type ImuT = crate::imu::invensense::Invensense<SPI, NcsPinT>;
+#[cfg(all(acquisition = "acquisition_fifo", imu = "imu_icm42688"))]
+pub type ImuT = crate::imu::icm42688::Icm42688<SPI, NcsPinT>;
+#[cfg(all(sensors = "sensors_marg", acquisition = "acquisition_fifo"))]
+compile_error!("FIFO acquisition has no magnetometer, use acquisition_single");
+#[cfg(all(acquisition = "acquisition_single", not(imu = "imu_mpu9250")))]
+compile_error!("single sample acquisition is MPU9250 only");
 
 pub type DebugPinT = DebugPinDef<PullNone, Output<PushPull, HighSpeed>>;
 
@@ -336,6 +366,9 @@ pub mod mydevice {
     pub enum Interrupt {
         EXTI15_10 = hal::pac::Interrupt::EXTI15_10 as u8,
         EXTI0 = hal::pac::Interrupt::EXTI0 as u8,
+        // software task dispatchers, no pins bound to these lines
+        EXTI1 = hal::pac::Interrupt::EXTI1 as u8,
+        EXTI3 = hal::pac::Interrupt::EXTI3 as u8,
 
         USART2_EXTI26 = hal::pac::Interrupt::USART2_EXTI26 as u8,
     }
@@ -57,3 +57,189 @@ impl Chrono for DwtClock {
         self.cc.to_ms(duration)
     }
 }
+
+/// Current DWT cycle count, wraps around
+#[inline]
+pub fn now() -> u32 {
+    let dwt = unsafe { &(*cortex_m::peripheral::DWT::ptr()) };
+    dwt.cyccnt.read()
+}
+
+/// Tasks with measured execution time
+#[derive(Copy, Clone)]
+pub enum Task {
+    Inner = 0,
+    Outer = 1,
+    Telemetry = 2,
+    Housekeeping = 3,
+}
+
+pub const TASKS: usize = 4;
+pub const JITTER_BINS: usize = 8;
+/// Upper bounds of jitter bins, us; the last bin takes the rest
+const JITTER_BOUNDS_US: [f32; JITTER_BINS - 1] =
+    [2., 5., 10., 20., 50., 100., 200.];
+
+/// Gap between idle loop iterations longer than this was spent in
+/// tasks or in command handling
+const IDLE_GAP_CYCLES: u32 = 200;
+/// Cycles CPU load is averaged over, ~0.5s at 64MHz
+const LOAD_WINDOW_CYCLES: u32 = 1 << 25;
+
+/// Execution time of a task in cycles, including tasks preempting it
+#[derive(Copy, Clone)]
+pub struct Timing {
+    min: u32,
+    max: u32,
+    total: u64,
+    runs: u32,
+}
+
+impl Timing {
+    #[inline]
+    pub const fn new() -> Self {
+        Timing {
+            min: u32::MAX,
+            max: 0,
+            total: 0,
+            runs: 0,
+        }
+    }
+
+    #[inline]
+    fn add(&mut self, cycles: u32) {
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+        self.total += cycles as u64;
+        self.runs = self.runs.wrapping_add(1);
+    }
+
+    /// min, avg, max in cycles; zeros before first run
+    fn cycles(&self) -> [f32; 3] {
+        if self.runs == 0 {
+            return [0., 0., 0.];
+        }
+        let avg = self.total as f32 / self.runs as f32;
+        [self.min as f32, avg, self.max as f32]
+    }
+}
+
+/// Loop statistics since start or last reset
+#[derive(Copy, Clone)]
+pub struct Perf {
+    cycles_per_us: f32,
+    tasks: [Timing; TASKS],
+    /// inner loop period change between consecutive steps
+    jitter: [u32; JITTER_BINS],
+    last_start: Option<u32>,
+    last_period: Option<u32>,
+    /// busy fraction of last load window, [0, 1]
+    load: f32,
+}
+
+impl Perf {
+    pub fn new<F: Into<Hertz<u32>>>(f: F) -> Self {
+        Perf {
+            cycles_per_us: f.into().0 as f32 / 1_000_000.,
+            tasks: [Timing::new(); TASKS],
+            jitter: [0; JITTER_BINS],
+            last_start: None,
+            last_period: None,
+            load: 0.,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.tasks = [Timing::new(); TASKS];
+        self.jitter = [0; JITTER_BINS];
+        self.last_start = None;
+        self.last_period = None;
+    }
+
+    /// `start` and `end` are `now()` at beginning and end of a task run
+    #[inline]
+    pub fn task(&mut self, task: Task, start: u32, end: u32) {
+        self.tasks[task as usize].add(end.wrapping_sub(start));
+    }
+
+    /// `start` is `now()` at beginning of inner loop step
+    pub fn step(&mut self, start: u32) {
+        if let Some(last) = self.last_start {
+            let period = start.wrapping_sub(last);
+            if let Some(last_period) = self.last_period {
+                let change = if period > last_period {
+                    period - last_period
+                } else {
+                    last_period - period
+                };
+                let us = change as f32 / self.cycles_per_us;
+                let bin = JITTER_BOUNDS_US
+                    .iter()
+                    .position(|bound| us < *bound)
+                    .unwrap_or(JITTER_BINS - 1);
+                self.jitter[bin] = self.jitter[bin].wrapping_add(1);
+            }
+            self.last_period = Some(period);
+        }
+        self.last_start = Some(start);
+    }
+
+    #[inline]
+    pub fn set_load(&mut self, load: f32) {
+        self.load = load;
+    }
+
+    /// inner, outer, telemetry, housekeeping: min, avg, max in us;
+    /// then CPU load and jitter bins
+    pub fn results(&self) -> [f32; TASKS * 3 + 1 + JITTER_BINS] {
+        let mut results = [0.; TASKS * 3 + 1 + JITTER_BINS];
+        for (i, timing) in self.tasks.iter().enumerate() {
+            for (j, cycles) in timing.cycles().iter().enumerate() {
+                results[i * 3 + j] = cycles / self.cycles_per_us;
+            }
+        }
+        results[TASKS * 3] = self.load;
+        for (i, count) in self.jitter.iter().enumerate() {
+            results[TASKS * 3 + 1 + i] = *count as f32;
+        }
+        results
+    }
+}
+
+/// Busy fraction of time seen from idle loop, to be called on every
+/// iteration of it
+pub struct Load {
+    last: Option<u32>,
+    idle: u32,
+    elapsed: u32,
+}
+
+impl Load {
+    #[inline]
+    pub const fn new() -> Self {
+        Load {
+            last: None,
+            idle: 0,
+            elapsed: 0,
+        }
+    }
+
+    /// Some(load) once per window
+    pub fn tick(&mut self, now: u32) -> Option<f32> {
+        let gap = match self.last.replace(now) {
+            Some(last) => now.wrapping_sub(last),
+            None => return None,
+        };
+        if gap < IDLE_GAP_CYCLES {
+            self.idle += gap;
+        }
+        self.elapsed = self.elapsed.saturating_add(gap);
+        if self.elapsed < LOAD_WINDOW_CYCLES {
+            return None;
+        }
+        let load = 1. - self.idle as f32 / self.elapsed as f32;
+        self.idle = 0;
+        self.elapsed = 0;
+        Some(load)
+    }
+}
@@ -1,3 +1,10 @@
+use crate::alignment;
+use crate::autotune;
+use crate::estimators;
+use crate::filters;
+use crate::health;
+use crate::imu;
+use crate::schedule;
 use crate::types;
 
 fn parse<T, E>(bytes: &[u8]) -> Result<T, E>
@@ -8,6 +15,10 @@ where
     T::from_str(v)
 }
 
+fn stick(percents: i32) -> f32 {
+    crate::utils::clamp(percents as f32 / 100., -1., 1.)
+}
+
 macro_rules! parse {
     (@cond $inp:ident $var:expr) => {
         $inp == $var.as_bytes()
@@ -92,23 +103,206 @@ impl Cmd {
                    ["tmoff"] => {
                        control.telemetry = false;
                    },
+                   // shared roll/pitch rate gains
                    ["pk=", pk:i32] => {
-                       control.pk = pk as f32;
+                       control.rate[0].p = pk as f32;
+                       control.rate[1].p = pk as f32;
                    },
                    ["ik=", ik:i32] => {
-                       control.ik = ik as f32;
+                       control.rate[0].i = ik as f32;
+                       control.rate[1].i = ik as f32;
                    },
                    ["dk=", dk:i32] => {
-                       control.dk = dk as f32;
+                       control.rate[0].d = dk as f32;
+                       control.rate[1].d = dk as f32;
                    },
-                   ["pipk=", pitch_pk:i32] => {
-                       control.pitch_pk = pitch_pk as f32;
+                   ["rxpk=", pk:i32] => {
+                       control.rate[0].p = pk as f32;
+                   },
+                   ["rxik=", ik:i32] => {
+                       control.rate[0].i = ik as f32;
+                   },
+                   ["rxdk=", dk:i32] => {
+                       control.rate[0].d = dk as f32;
+                   },
+                   ["rypk=", pk:i32] => {
+                       control.rate[1].p = pk as f32;
+                   },
+                   ["ryik=", ik:i32] => {
+                       control.rate[1].i = ik as f32;
+                   },
+                   ["rydk=", dk:i32] => {
+                       control.rate[1].d = dk as f32;
+                   },
+                   ["rzpk=", pk:i32] => {
+                       control.rate[2].p = pk as f32;
+                   },
+                   ["rzik=", ik:i32] => {
+                       control.rate[2].i = ik as f32;
+                   },
+                   ["rzdk=", dk:i32] => {
+                       control.rate[2].d = dk as f32;
                    },
                    ["rpk=", roll_pk:i32] => {
-                       control.roll_pk = roll_pk as f32;
+                       control.angle[0] = roll_pk as f32;
+                   },
+                   ["pipk=", pitch_pk:i32] => {
+                       control.angle[1] = pitch_pk as f32;
                    },
                    ["ypk=", yaw_pk:i32] => {
-                       control.yaw_pk = yaw_pk as f32;
+                       control.angle[2] = yaw_pk as f32;
+                   },
+                   ["mt=", max_tilt:i32] => {
+                       control.max_tilt_degrees = max_tilt as f32;
+                   },
+                   ["mr=", max_rate:i32] => {
+                       control.max_rate_degrees = max_rate as f32;
+                   },
+                   ["mc=", max_corr:i32] => {
+                       control.max_correction = max_corr as f32;
+                   },
+                   ["il=", i_limit:i32] => {
+                       control.i_limit = i_limit as f32;
+                   },
+                   ["ithr=", idle_thrust:i32] => {
+                       control.idle_thrust = idle_thrust as f32;
+                   },
+                   // low-pass kind: 0 - none, 1 - PT1, 2 - biquad, 3 - moving average
+                   ["dtt=", kind:i32] => {
+                       if let Some(kind) = filters::Kind::from_i32(kind) {
+                           control.filters.dterm.kind = kind;
+                       }
+                   },
+                   ["dtf=", cutoff_hz:i32] => {
+                       control.filters.dterm.cutoff_hz = cutoff_hz as f32;
+                   },
+                   ["gft=", kind:i32] => {
+                       if let Some(kind) = filters::Kind::from_i32(kind) {
+                           control.filters.gyro.kind = kind;
+                       }
+                   },
+                   ["gff=", cutoff_hz:i32] => {
+                       control.filters.gyro.cutoff_hz = cutoff_hz as f32;
+                   },
+                   ["aft=", kind:i32] => {
+                       if let Some(kind) = filters::Kind::from_i32(kind) {
+                           control.filters.accel.kind = kind;
+                       }
+                   },
+                   ["aff=", cutoff_hz:i32] => {
+                       control.filters.accel.cutoff_hz = cutoff_hz as f32;
+                   },
+                   // 0 disables gyro notch
+                   ["nf=", center_hz:i32] => {
+                       control.filters.notch.center_hz = center_hz as f32;
+                   },
+                   // notch Q, in hundredths
+                   ["nq=", q:i32] => {
+                       control.filters.notch.q = q as f32 / 100.;
+                   },
+                   // number of tracked peaks, 0 disables dynamic notch
+                   ["dnc=", count:usize] => {
+                       control.filters.dyn_notch.count = count;
+                   },
+                   ["dnm=", min_hz:i32] => {
+                       control.filters.dyn_notch.min_hz = min_hz as f32;
+                   },
+                   ["dnq=", q:i32] => {
+                       control.filters.dyn_notch.q = q as f32 / 100.;
+                   },
+                   ["tpab=", breakpoint:i32] => {
+                       control.tpa.breakpoint = breakpoint as f32;
+                   },
+                   // attenuation at max thrust, percents
+                   ["tpar=", rate:i32] => {
+                       control.tpa.rate =
+                           crate::utils::clamp(rate as f32 / 100., 0., 1.);
+                   },
+                   ["tpam=", max_thrust:i32] => {
+                       control.tpa.max_thrust = max_thrust as f32;
+                   },
+                   // tpat=index,thrust,percents
+                   ["tpat=", point:types::TpaPoint] => {
+                       control.tpa.set_point(&point);
+                   },
+                   ["tpac"] => {
+                       control.tpa.points = 0;
+                   },
+                   ["acro"] => {
+                       control.mode = types::FlightMode::Acro;
+                   },
+                   ["angle"] => {
+                       control.mode = types::FlightMode::Angle;
+                   },
+                   ["horizon"] => {
+                       control.mode = types::FlightMode::Horizon;
+                   },
+                   // sticks, percents
+                   ["sr=", roll:i32] => {
+                       control.sticks.roll = stick(roll);
+                   },
+                   ["sp=", pitch:i32] => {
+                       control.sticks.pitch = stick(pitch);
+                   },
+                   ["sy=", yaw:i32] => {
+                       control.sticks.yaw = stick(yaw);
+                   },
+                   ["aux=", aux:i32] => {
+                       control.set_aux(stick(aux));
+                   },
+                   ["rp=", profile:usize] => {
+                       if profile < types::RATE_PROFILES {
+                           control.rate_profile = profile;
+                       }
+                   },
+                   // active rate profile, percents
+                   ["rcx=", rc_rate:i32] => {
+                       control.active_rates_mut().axes[0].rc_rate =
+                           rc_rate as f32 / 100.;
+                   },
+                   ["srx=", super_rate:i32] => {
+                       control.active_rates_mut().axes[0].super_rate =
+                           super_rate as f32 / 100.;
+                   },
+                   ["exx=", expo:i32] => {
+                       control.active_rates_mut().axes[0].expo =
+                           expo as f32 / 100.;
+                   },
+                   ["rcy=", rc_rate:i32] => {
+                       control.active_rates_mut().axes[1].rc_rate =
+                           rc_rate as f32 / 100.;
+                   },
+                   ["sry=", super_rate:i32] => {
+                       control.active_rates_mut().axes[1].super_rate =
+                           super_rate as f32 / 100.;
+                   },
+                   ["exy=", expo:i32] => {
+                       control.active_rates_mut().axes[1].expo =
+                           expo as f32 / 100.;
+                   },
+                   ["rcz=", rc_rate:i32] => {
+                       control.active_rates_mut().axes[2].rc_rate =
+                           rc_rate as f32 / 100.;
+                   },
+                   ["srz=", super_rate:i32] => {
+                       control.active_rates_mut().axes[2].super_rate =
+                           super_rate as f32 / 100.;
+                   },
+                   ["exz=", expo:i32] => {
+                       control.active_rates_mut().axes[2].expo =
+                           expo as f32 / 100.;
+                   },
+                   ["yh"] => {
+                       control.yaw_mode = types::YawMode::Heading;
+                   },
+                   ["yr"] => {
+                       control.yaw_mode = types::YawMode::Rate;
+                   },
+                   ["yt=", yt:i32] => {
+                       control.target_degrees.yaw = yt as f32;
+                   },
+                   ["yrt=", yrt:i32] => {
+                       control.yaw_rate_degrees = yrt as f32;
                    },
                    ["tthurst=", thrust:i32] => {
                        control.thrust = thrust as f32;
@@ -116,6 +310,246 @@ impl Cmd {
                    ["pt=", pt:i32] => {
                        control.target_degrees.pitch = pt as f32;
                    },
+                   // at=axis starts relay autotune of x, y or z rate loop
+                   ["at=", axis:usize] => {
+                       if axis < 3 {
+                           control.autotune.axis = Some(axis);
+                           control.autotune_restart = true;
+                       }
+                   },
+                   ["atoff"] => {
+                       control.autotune.axis = None;
+                   },
+                   ["atd=", amplitude:i32] => {
+                       control.autotune.amplitude = amplitude as f32;
+                   },
+                   // deg/s
+                   ["ath=", hysteresis:i32] => {
+                       control.autotune.hysteresis =
+                           crate::utils::to_rads(hysteresis as f32);
+                   },
+                   // 0 - Ziegler-Nichols, 1 - Tyreus-Luyben
+                   ["atr=", rule:i32] => {
+                       if let Some(rule) = autotune::Rule::from_i32(rule) {
+                           control.autotune.rule = rule;
+                       }
+                   },
+                   ["atok"] => {
+                       requests = Some(types::Requests::AutotuneAccept);
+                   },
+                   ["at"] => {
+                       requests = Some(types::Requests::Autotune);
+                   },
+                   // full-scale ranges: 250, 500, 1000, 2000 deg/s
+                   ["gr=", dps:i32] => {
+                       if let Some(range) = imu::GyroRange::from_dps(dps) {
+                           control.imu.gyro_range = range;
+                       }
+                   },
+                   // 2, 4, 8, 16 g
+                   ["ar=", g:i32] => {
+                       if let Some(range) = imu::AccelRange::from_g(g) {
+                           control.imu.accel_range = range;
+                       }
+                   },
+                   // 1 - 184Hz .. 6 - 5Hz
+                   ["dlpf=", dlpf:i32] => {
+                       if imu::Config::valid_dlpf(dlpf) {
+                           control.imu.dlpf = dlpf as u8;
+                       }
+                   },
+                   // sample rate 1kHz / (1 + divisor)
+                   ["srd=", divisor:u8] => {
+                       control.imu.divisor = divisor;
+                   },
+                   ["imu"] => {
+                       requests = Some(types::Requests::Imu);
+                   },
+                   // loop steps between WHO_AM_I checks, 0 - off
+                   ["hcp=", period:u32] => {
+                       control.health.check_period = period;
+                   },
+                   // failing steps in a row before failsafe
+                   ["hmf=", failures:u32] => {
+                       if failures > 0 {
+                           control.health.max_failures = failures;
+                       }
+                   },
+                   // 0 - motors off, 1 - idle thrust
+                   ["hfs=", action:i32] => {
+                       if let Some(action) = health::Failsafe::from_i32(action) {
+                           control.health.failsafe = action;
+                       }
+                   },
+                   ["hclr"] => {
+                       control.clear_failsafe = true;
+                   },
+                   ["health"] => {
+                       requests = Some(types::Requests::Health);
+                   },
+                   // sensor mounting: 0..3 - CW0..CW270, 4..7 - flipped
+                   ["al=", preset:i32] => {
+                       if let Some(preset) = alignment::Preset::from_i32(preset) {
+                           control.alignment.preset = preset;
+                       }
+                   },
+                   // mounting trim, hundredths of degree
+                   ["alr=", trim:i32] => {
+                       control.alignment.trim_degrees[0] = trim as f32 / 100.;
+                   },
+                   ["alp=", trim:i32] => {
+                       control.alignment.trim_degrees[1] = trim as f32 / 100.;
+                   },
+                   ["aly=", trim:i32] => {
+                       control.alignment.trim_degrees[2] = trim as f32 / 100.;
+                   },
+                   // 0 - DCM, 1 - Mahony, 2 - Madgwick, 3 - EKF
+                   ["est=", kind:i32] => {
+                       if let Some(kind) = estimators::Kind::from_i32(kind) {
+                           control.estimator.kind = kind;
+                       }
+                   },
+                   // hundredths
+                   ["mhp=", kp:i32] => {
+                       control.estimator.kp = kp as f32 / 100.;
+                   },
+                   ["mhi=", ki:i32] => {
+                       control.estimator.ki = ki as f32 / 100.;
+                   },
+                   ["mgb=", beta:i32] => {
+                       control.estimator.beta = beta as f32 / 100.;
+                   },
+                   // 6 or 9 EKF states, 9 estimates earth magnetic field
+                   ["ekn=", n:usize] => {
+                       if n == 6 || n == 9 {
+                           control.estimator.ekf.mag_states = n == 9;
+                       }
+                   },
+                   // EKF noises, ten-thousandths
+                   ["ekg=", v:i32] => {
+                       control.estimator.ekf.gyro_noise = v as f32 / 10000.;
+                   },
+                   ["ekb=", v:i32] => {
+                       control.estimator.ekf.bias_noise = v as f32 / 10000.;
+                   },
+                   ["ekf=", v:i32] => {
+                       control.estimator.ekf.field_noise = v as f32 / 10000.;
+                   },
+                   ["eka=", v:i32] => {
+                       control.estimator.ekf.accel_noise = v as f32 / 10000.;
+                   },
+                   ["ekm=", v:i32] => {
+                       control.estimator.ekf.mag_noise = v as f32 / 10000.;
+                   },
+                   // vibration RMS where accel trust starts to drop and
+                   // where it is least, hundredths of m/s^2
+                   ["vbl=", low:i32] => {
+                       control.vibration.low = low as f32 / 100.;
+                   },
+                   ["vbh=", high:i32] => {
+                       control.vibration.high = high as f32 / 100.;
+                   },
+                   // per-axis Kalman instead of estimator for roll, pitch
+                   ["kfx=", on:i32] => {
+                       control.estimator.kalman.roll = on != 0;
+                   },
+                   ["kfy=", on:i32] => {
+                       control.estimator.kalman.pitch = on != 0;
+                   },
+                   // Kalman noises, ten-thousandths
+                   ["kfqa=", q:i32] => {
+                       control.estimator.kalman.q_angle = q as f32 / 10000.;
+                   },
+                   ["kfqb=", q:i32] => {
+                       control.estimator.kalman.q_bias = q as f32 / 10000.;
+                   },
+                   ["kfr=", r:i32] => {
+                       control.estimator.kalman.r = r as f32 / 10000.;
+                   },
+                   // rotate through all orientations in between
+                   ["magcal"] => {
+                       control.mag_calibrating = true;
+                   },
+                   ["magcalok"] => {
+                       control.mag_calibrating = false;
+                   },
+                   // board at rest while it warms up, at least 5 degrees
+                   ["gtcal"] => {
+                       control.gyro_temp_calibrating = true;
+                   },
+                   ["gtcalok"] => {
+                       control.gyro_temp_calibrating = false;
+                   },
+                   // board level and at rest, motors stop meanwhile
+                   ["restcal"] => {
+                       control.rest_calibrating = true;
+                   },
+                   ["gt"] => {
+                       requests = Some(types::Requests::GyroTemp);
+                   },
+                   // gyro bias slope, thousandths of deg/s per degree
+                   ["gtx=", slope:i32] => {
+                       control.gyro_temp.slopes[0] =
+                           crate::utils::to_rads(slope as f32 / 1000.);
+                   },
+                   ["gty=", slope:i32] => {
+                       control.gyro_temp.slopes[1] =
+                           crate::utils::to_rads(slope as f32 / 1000.);
+                   },
+                   ["gtz=", slope:i32] => {
+                       control.gyro_temp.slopes[2] =
+                           crate::utils::to_rads(slope as f32 / 1000.);
+                   },
+                   ["mag"] => {
+                       requests = Some(types::Requests::Mag);
+                   },
+                   // hard iron bias, magnetometer units
+                   ["mbx=", bias:i32] => {
+                       control.mag.bias[0] = bias as f32;
+                   },
+                   ["mby=", bias:i32] => {
+                       control.mag.bias[1] = bias as f32;
+                   },
+                   ["mbz=", bias:i32] => {
+                       control.mag.bias[2] = bias as f32;
+                   },
+                   // soft iron scale, percents
+                   ["msx=", scale:i32] => {
+                       control.mag.scale[0] = scale as f32 / 100.;
+                   },
+                   ["msy=", scale:i32] => {
+                       control.mag.scale[1] = scale as f32 / 100.;
+                   },
+                   ["msz=", scale:i32] => {
+                       control.mag.scale[2] = scale as f32 / 100.;
+                   },
+                   // inner loop steps per outer loop and telemetry step
+                   ["odiv=", divider:u32] => {
+                       if divider > 0 {
+                           control.schedule.outer_divider = divider;
+                       }
+                   },
+                   ["tdiv=", divider:u32] => {
+                       if divider > 0 {
+                           control.schedule.telemetry_divider = divider;
+                       }
+                   },
+                   // 4 - 100Hz
+                   ["hkhz=", hz:u32] => {
+                       if schedule::Config::valid_housekeeping_hz(hz) {
+                           control.schedule.housekeeping_hz = hz;
+                       }
+                   },
+                   ["sched"] => {
+                       requests = Some(types::Requests::Schedule);
+                   },
+                   // task timings, CPU load and inner loop jitter
+                   ["perf"] => {
+                       requests = Some(types::Requests::Perf);
+                   },
+                   ["perfclr"] => {
+                       requests = Some(types::Requests::PerfReset);
+                   },
                    ["status"] => {
                        requests = Some(types::Requests::Status);
                    },
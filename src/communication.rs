@@ -13,6 +13,19 @@ pub fn channel(ch: crate::boards::TxCh, tx: crate::boards::TxUsart) -> Channel {
     Channel::create(ch, tx)
 }
 
+// sends through shared channel, unless someone else has taken it
+pub fn send_shared<M, F>(shared: &mut M, sender: F)
+where
+    M: rtic::Mutex<T = Option<Channel>>,
+    F: FnOnce(Channel) -> Channel,
+{
+    shared.lock(|maybe_channel| {
+        if let Some(channel) = maybe_channel.take() {
+            *maybe_channel = Some(sender(channel));
+        }
+    });
+}
+
 enum TransferState {
     Ready(TxReady),
     MaybeBusy(TxBusy),
@@ -33,6 +46,14 @@ impl Channel {
         Channel::with_state(state)
     }
 
+    // previous transfer is done, so next `send` won't be skipped
+    pub fn is_ready(&self) -> bool {
+        match &self.state {
+            TransferState::Ready(_) => true,
+            TransferState::MaybeBusy(transfer) => transfer.is_done(),
+        }
+    }
+
     pub fn send<F>(self, mut buffer_filler: F) -> Self
     where
         F: for<'a> FnMut<(&'a mut TxBuffer,), Output = ()>,
@@ -1,38 +1,407 @@
 use crate::ahrs::AhrsResult;
+use crate::filters::{self, Filter};
 use crate::prelude::*;
+use crate::quaternion::Quaternion;
 use crate::types;
-use crate::utils::to_rads;
+use crate::utils::{clamp, to_rads};
+use libm::fabsf;
 
-// "body rate" controller from f3-eva
-// XXX: return types?
-// returns (corrections, errors)
-pub fn body_rate(
+// yaw stick travel around center that still holds heading
+const YAW_DEADBAND: f32 = 0.05;
+
+pub const fn create() -> BodyRate {
+    BodyRate::new()
+}
+
+// Outer loop: stick input and attitude to body rate setpoint, rad/s.
+pub fn setpoint(state: &types::State, control: &types::Control) -> [f32; 3] {
+    let mut setpoint = match control.mode {
+        types::FlightMode::Acro => acro(control),
+        types::FlightMode::Angle => angle(state, control),
+        types::FlightMode::Horizon => horizon(state, control),
+    };
+    setpoint[2] = yaw(state, control);
+
+    let max_rate = to_rads(control.max_rate_degrees);
+    for sp in setpoint.iter_mut() {
+        *sp = clamp(*sp, -max_rate, max_rate);
+    }
+    setpoint
+}
+
+// Sticks are body rates, shaped by active rate profile.
+pub fn acro(control: &types::Control) -> [f32; 3] {
+    let profile = control.active_rates();
+    [
+        to_rads(stick_rate(&profile.axes[0], control.sticks.roll)),
+        to_rads(stick_rate(&profile.axes[1], control.sticks.pitch)),
+        to_rads(stick_rate(&profile.axes[2], control.sticks.yaw)),
+    ]
+}
+
+// Betaflight rate model: normalized stick to deg/s
+pub fn stick_rate(rates: &types::Rates, stick: f32) -> f32 {
+    let abs = clamp(fabsf(stick), 0., 1.);
+    let expo = rates.expo;
+    let rc = stick * abs * abs * abs * expo + stick * (1. - expo);
+
+    let mut rc_rate = rates.rc_rate;
+    if rc_rate > 2. {
+        rc_rate += 14.54 * (rc_rate - 2.);
+    }
+    let mut rate = 200. * rc_rate * rc;
+    if rates.super_rate != 0. {
+        let k = 1. / clamp(1. - abs * rates.super_rate, 0.01, 1.);
+        rate *= k;
+    }
+    rate
+}
+
+// Sticks tilt `target_degrees` up to `max_tilt_degrees`.
+pub fn angle(state: &types::State, control: &types::Control) -> [f32; 3] {
+    let error = attitude_error(state, control);
+    [error[0] * control.angle[0], error[1] * control.angle[1], 0.]
+}
+
+// Body frame rotation from current attitude to target one, rad.
+// Computed on quaternions, so it is valid at any attitude and
+// heading wraps around the short way.
+fn attitude_error(state: &types::State, control: &types::Control) -> [f32; 3] {
+    let max_tilt = control.max_tilt_degrees;
+    let roll = control.target_degrees.roll + control.sticks.roll * max_tilt;
+    let pitch = control.target_degrees.pitch + control.sticks.pitch * max_tilt;
+    let roll_target = to_rads(clamp(roll, -max_tilt, max_tilt));
+    let pitch_target = to_rads(clamp(pitch, -max_tilt, max_tilt));
+    let yaw_target = match control.yaw_mode {
+        types::YawMode::Heading => to_rads(control.target_degrees.yaw),
+        // heading is not held, so it doesn't contribute to the error
+        types::YawMode::Rate => state.ahrs.ypr.yaw,
+    };
+    let target = Quaternion::from_euler(yaw_target, pitch_target, roll_target);
+    state.ahrs.q.error_to(&target)
+}
+
+// Self-leveling fades out as sticks move away from center.
+pub fn horizon(state: &types::State, control: &types::Control) -> [f32; 3] {
+    let level = angle(state, control);
+    let rate = acro(control);
+    let deflection =
+        fabsf(control.sticks.roll).max(fabsf(control.sticks.pitch));
+    let k = 1. - clamp(deflection, 0., 1.);
+    [
+        k * level[0] + (1. - k) * rate[0],
+        k * level[1] + (1. - k) * rate[1],
+        0.,
+    ]
+}
+
+// Heading is held only with yaw stick centered, otherwise it would
+// pull back against the turn.
+fn yaw(state: &types::State, control: &types::Control) -> f32 {
+    let rates = &control.active_rates().axes[2];
+    let stick = to_rads(stick_rate(rates, control.sticks.yaw));
+    match (control.mode, control.yaw_mode) {
+        (types::FlightMode::Acro, _) => stick,
+        (_, types::YawMode::Rate) => to_rads(control.yaw_rate_degrees) + stick,
+        (_, types::YawMode::Heading) if yaw_stick_active(control) => stick,
+        (_, types::YawMode::Heading) => {
+            attitude_error(state, control)[2] * control.angle[2]
+        }
+    }
+}
+
+#[inline]
+fn yaw_stick_active(control: &types::Control) -> bool {
+    fabsf(control.sticks.yaw) > YAW_DEADBAND
+}
+
+// New `target_degrees.yaw` while yaw stick turns in `YawMode::Heading`:
+// current heading, so it is held where the turn ends.
+pub fn turned_heading(
     state: &types::State,
     control: &types::Control,
-) -> ([f32; 3], [f32; 3]) {
-    let pitch_target = to_rads(control.target_degrees.pitch);
-    let roll_target = to_rads(control.target_degrees.roll);
-    let yaw_target = to_rads(control.target_degrees.yaw);
-
-    let pitch_err = (pitch_target - state.ahrs.ypr.pitch) * control.pitch_pk;
-    let yaw_err = (yaw_target - state.ahrs.ypr.yaw) * control.yaw_pk;
-    let roll_err = (roll_target - state.ahrs.ypr.roll) * control.roll_pk;
-
-    // XXX?
-    let x_err = roll_err - state.ahrs.biased_gyro[0];
-    let y_err = pitch_err - state.ahrs.biased_gyro[1];
-    let z_err = yaw_err - state.ahrs.biased_gyro[2];
-    // ?XXX
-
-    let i_comp = 0.;
-    let delta_x = x_err - state.errors[0];
-    let delta_y = y_err - state.errors[1];
-    let delta_z = z_err - state.errors[2];
-    let x_corr =
-        x_err * control.pk + i_comp * control.ik + control.dk * delta_x;
-    let y_corr =
-        y_err * control.pk + i_comp * control.ik + control.dk * delta_y;
-    let z_corr = 0.; // z_err * control.pk + i_comp * control.ik + control.dk * delta_z;
-
-    ([x_corr, y_corr, z_corr], [x_err, y_err, z_err])
+) -> Option<f32> {
+    let holding = control.mode != types::FlightMode::Acro
+        && control.yaw_mode == types::YawMode::Heading;
+    if holding && yaw_stick_active(control) {
+        Some(state.ahrs.ypr.yaw.to_degrees())
+    } else {
+        None
+    }
+}
+
+// Throttle PID attenuation multiplier for current thrust
+pub fn tpa(control: &types::Control) -> f32 {
+    let tpa = &control.tpa;
+    let thrust = control.thrust;
+    if tpa.points > 0 {
+        return interpolate(&tpa.table[..tpa.points], thrust);
+    }
+    if thrust <= tpa.breakpoint || tpa.max_thrust <= tpa.breakpoint {
+        return 1.0;
+    }
+    let k = (thrust - tpa.breakpoint) / (tpa.max_thrust - tpa.breakpoint);
+    1.0 - clamp(tpa.rate, 0., 1.) * clamp(k, 0., 1.)
+}
+
+// piecewise linear, flat outside of the table
+fn interpolate(table: &[(f32, f32)], x: f32) -> f32 {
+    let (x0, y0) = table[0];
+    if x <= x0 {
+        return y0;
+    }
+    for w in table.windows(2) {
+        let ((x0, y0), (x1, y1)) = (w[0], w[1]);
+        if x <= x1 {
+            if x1 <= x0 {
+                return y1;
+            }
+            return y0 + (y1 - y0) * (x - x0) / (x1 - x0);
+        }
+    }
+    table[table.len() - 1].1
+}
+
+pub struct Pid {
+    // integral of the error, rad
+    integral: f32,
+    // last filtered measurement, none since reset
+    measurement: Option<f32>,
+    d_filter: filters::LowPass,
+}
+
+impl Pid {
+    #[inline]
+    pub const fn new() -> Self {
+        Pid {
+            integral: 0.0,
+            measurement: None,
+            d_filter: filters::LowPass::new(),
+        }
+    }
+
+    // next step starts over, without D from before
+    #[inline]
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.measurement = None;
+        self.d_filter.reset();
+    }
+
+    #[inline]
+    pub fn configure(&mut self, d_filter: &filters::Config, sample_hz: f32) {
+        self.d_filter.configure(d_filter, sample_hz);
+    }
+
+    // D is taken on filtered measurement, so setpoint changes don't kick
+    pub fn update(
+        &mut self,
+        setpoint: f32,
+        measurement: f32,
+        gains: &types::Gains,
+        dt: f32,
+        i_limit: f32,
+        saturated: bool,
+    ) -> f32 {
+        let error = setpoint - measurement;
+        self.integrate(error, gains, dt, i_limit, saturated);
+
+        let filtered = self.d_filter.apply(measurement);
+        let derivative = match self.measurement {
+            Some(previous) if dt > 0. => (filtered - previous) / dt,
+            _ => 0.,
+        };
+        self.measurement = Some(filtered);
+
+        error * gains.p + self.integral * gains.i - derivative * gains.d
+    }
+
+    // Conditional integration: while mixer is saturated integral is only
+    // allowed to unwind; I contribution is clamped to `i_limit` in any case.
+    fn integrate(
+        &mut self,
+        error: f32,
+        gains: &types::Gains,
+        dt: f32,
+        i_limit: f32,
+        saturated: bool,
+    ) {
+        let winds_up = error * self.integral > 0.;
+        if saturated && winds_up {
+            return;
+        }
+        let max_integral = if gains.i > 0. { i_limit / gains.i } else { 0. };
+        let integral = self.integral + error * dt;
+        self.integral = clamp(integral, -max_integral, max_integral);
+    }
+}
+
+// Inner loop: "body rate" controller from f3-eva
+pub struct BodyRate {
+    pids: [Pid; 3],
+}
+
+impl BodyRate {
+    #[inline]
+    pub const fn new() -> Self {
+        BodyRate {
+            pids: [Pid::new(), Pid::new(), Pid::new()],
+        }
+    }
+
+    #[inline]
+    pub fn reset(&mut self) {
+        for pid in self.pids.iter_mut() {
+            pid.reset();
+        }
+    }
+
+    #[inline]
+    pub fn reset_axis(&mut self, axis: usize) {
+        self.pids[axis].reset();
+    }
+
+    // XXX: return types?
+    // returns (corrections, errors)
+    pub fn update(
+        &mut self,
+        setpoint: &[f32; 3],
+        state: &types::State,
+        control: &types::Control,
+    ) -> ([f32; 3], [f32; 3]) {
+        let gyro = state.ahrs.biased_gyro;
+        let errors = [
+            setpoint[0] - gyro[0],
+            setpoint[1] - gyro[1],
+            setpoint[2] - gyro[2],
+        ];
+
+        // on the ground integral would only wind up: zero limit keeps it
+        // at zero, P and D still work
+        let i_limit = if control.thrust <= control.idle_thrust {
+            0.
+        } else {
+            control.i_limit
+        };
+        let dt = state.ahrs.dt_s;
+        let max_corr = control.max_correction;
+        let mut corrections = [0.0; 3];
+        for i in 0..3 {
+            let mut gains = control.rate[i];
+            gains.p *= state.tpa;
+            gains.d *= state.tpa;
+            let pid = &mut self.pids[i];
+            pid.configure(&control.filters.dterm, state.ahrs.sample_rate_hz);
+            let corr = pid.update(
+                setpoint[i],
+                gyro[i],
+                &gains,
+                dt,
+                i_limit,
+                state.saturated,
+            );
+            corrections[i] = clamp(corr, -max_corr, max_corr);
+        }
+
+        (corrections, errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // craft turned by `yaw` away from zero target heading
+    fn turned(yaw: f32) -> (types::State, types::Control) {
+        let mut state = types::State::new();
+        state.ahrs.q = Quaternion::from_euler(yaw, 0., 0.);
+        state.ahrs.ypr.yaw = yaw;
+        let mut control = types::Control::new();
+        control.angle = [4., 4., 4.];
+        (state, control)
+    }
+
+    #[test]
+    fn held_yaw_stick_turns() {
+        let (state, mut control) = turned(0.5);
+        control.sticks.yaw = 0.5;
+        let rates = &control.active_rates().axes[2];
+        let stick = to_rads(stick_rate(rates, 0.5));
+        assert!(stick > 0.);
+        assert_eq!(setpoint(&state, &control)[2], stick);
+        let heading = turned_heading(&state, &control).unwrap();
+        assert!((heading - 0.5f32.to_degrees()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn centered_yaw_stick_holds_heading() {
+        let (state, mut control) = turned(0.5);
+        control.sticks.yaw = YAW_DEADBAND / 2.;
+        assert!(setpoint(&state, &control)[2] < 0.);
+        assert!(turned_heading(&state, &control).is_none());
+
+        // released where the turn ended
+        control.target_degrees.yaw = 0.5f32.to_degrees();
+        assert!(fabsf(setpoint(&state, &control)[2]) < 1e-3);
+    }
+
+    fn rate_control(thrust: f32) -> (types::State, types::Control) {
+        let mut state = types::State::new();
+        state.ahrs.dt_s = 0.001;
+        state.ahrs.sample_rate_hz = 1000.;
+        let mut control = types::Control::new();
+        control.rate = [types::Gains::new(); 3];
+        for gains in control.rate.iter_mut() {
+            gains.i = 1.;
+        }
+        control.idle_thrust = 0.1;
+        control.thrust = thrust;
+        (state, control)
+    }
+
+    #[test]
+    fn no_integral_at_idle() {
+        let (state, control) = rate_control(0.1);
+        let mut body_rate = BodyRate::new();
+        for _ in 0..10 {
+            body_rate.update(&[1., 1., 1.], &state, &control);
+        }
+        assert!(body_rate.pids.iter().all(|pid| pid.integral == 0.));
+    }
+
+    #[test]
+    fn integral_is_dropped_at_idle() {
+        let (state, mut control) = rate_control(0.5);
+        let mut body_rate = BodyRate::new();
+        body_rate.update(&[1., 1., 1.], &state, &control);
+        assert!(body_rate.pids[0].integral > 0.);
+        control.thrust = 0.;
+        body_rate.update(&[1., 1., 1.], &state, &control);
+        assert_eq!(body_rate.pids[0].integral, 0.);
+    }
+
+    #[test]
+    fn no_derivative_kick_after_reset() {
+        let gains = types::Gains {
+            p: 0.,
+            i: 0.,
+            d: 1.,
+        };
+        let mut pid = Pid::new();
+        pid.update(0., 5., &gains, 0.001, 0., false);
+        pid.reset();
+        assert_eq!(pid.update(0., -5., &gains, 0.001, 0., false), 0.);
+        assert!(pid.update(0., -4., &gains, 0.001, 0., false) < 0.);
+    }
+
+    #[test]
+    fn heading_is_not_latched_in_acro_or_rate() {
+        let (state, mut control) = turned(0.5);
+        control.sticks.yaw = 0.5;
+        control.mode = types::FlightMode::Acro;
+        assert!(turned_heading(&state, &control).is_none());
+        control.mode = types::FlightMode::Angle;
+        control.yaw_mode = types::YawMode::Rate;
+        assert!(turned_heading(&state, &control).is_none());
+    }
 }
@@ -0,0 +1,350 @@
+// Dynamic gyro notch: tracks strongest vibration peaks with FFT
+// and retunes per-axis notch filters to follow them.
+use core::f32::consts::PI;
+use libm::{cosf, fabsf, sinf};
+
+use crate::filters::{self, Filter};
+
+const FFT_SIZE: usize = 64;
+const HALF: usize = FFT_SIZE / 2;
+// new FFT after this many samples, one axis at a time
+const HOP: usize = FFT_SIZE / 4;
+pub const MAX_PEAKS: usize = 3;
+// peak has to stand out of spectrum median by this factor
+const PEAK_THRESHOLD: f32 = 4.;
+// smoothing of tracked frequencies
+const TRACK_K: f32 = 0.5;
+// analyses in a row without a peak before slot is dropped
+const MAX_MISSES: u8 = 8;
+// peak continues a tracked one this close to it, part of its frequency
+const MATCH_RATIO: f32 = 0.25;
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Config {
+    // 0 disables dynamic notch
+    pub count: usize,
+    pub min_hz: f32,
+    pub q: f32,
+}
+
+impl Config {
+    #[inline]
+    pub const fn new() -> Self {
+        Config {
+            count: 0,
+            min_hz: 40.,
+            q: 3.,
+        }
+    }
+}
+
+pub struct DynNotch {
+    config: Config,
+    sample_hz: f32,
+    window: [[f32; FFT_SIZE]; 3],
+    pos: usize,
+    since_fft: usize,
+    axis: usize,
+    hann: [f32; FFT_SIZE],
+    twiddle: [(f32, f32); HALF],
+    re: [f32; FFT_SIZE],
+    im: [f32; FFT_SIZE],
+    // tracked peak frequencies, Hz, 0 if not tracked
+    peaks: [[f32; MAX_PEAKS]; 3],
+    // analyses in a row that found nothing for a slot
+    misses: [[u8; MAX_PEAKS]; 3],
+    notches: [[filters::Notch; MAX_PEAKS]; 3],
+}
+
+impl DynNotch {
+    pub fn new() -> Self {
+        let mut hann = [0.0; FFT_SIZE];
+        for (i, h) in hann.iter_mut().enumerate() {
+            *h = 0.5 - 0.5 * cosf(2. * PI * i as f32 / FFT_SIZE as f32);
+        }
+        let mut twiddle = [(0.0, 0.0); HALF];
+        for (i, t) in twiddle.iter_mut().enumerate() {
+            let a = -2. * PI * i as f32 / FFT_SIZE as f32;
+            *t = (cosf(a), sinf(a));
+        }
+        DynNotch {
+            config: Config::new(),
+            sample_hz: 0.0,
+            window: [[0.0; FFT_SIZE]; 3],
+            pos: 0,
+            since_fft: 0,
+            axis: 0,
+            hann,
+            twiddle,
+            re: [0.0; FFT_SIZE],
+            im: [0.0; FFT_SIZE],
+            peaks: [[0.0; MAX_PEAKS]; 3],
+            misses: [[0; MAX_PEAKS]; 3],
+            notches: [
+                [
+                    filters::Notch::new(),
+                    filters::Notch::new(),
+                    filters::Notch::new(),
+                ],
+                [
+                    filters::Notch::new(),
+                    filters::Notch::new(),
+                    filters::Notch::new(),
+                ],
+                [
+                    filters::Notch::new(),
+                    filters::Notch::new(),
+                    filters::Notch::new(),
+                ],
+            ],
+        }
+    }
+
+    pub fn configure(&mut self, config: &Config, sample_hz: f32) {
+        if self.config == *config && self.sample_hz == sample_hz {
+            return;
+        }
+        self.config = *config;
+        self.sample_hz = sample_hz;
+        self.peaks = [[0.0; MAX_PEAKS]; 3];
+        self.misses = [[0; MAX_PEAKS]; 3];
+        self.retune();
+    }
+
+    #[inline]
+    pub fn peaks(&self) -> [[f32; MAX_PEAKS]; 3] {
+        self.peaks
+    }
+
+    pub fn apply(&mut self, gyro: &[f32; 3]) -> [f32; 3] {
+        if self.config.count == 0 {
+            return *gyro;
+        }
+        for axis in 0..3 {
+            self.window[axis][self.pos] = gyro[axis];
+        }
+        self.pos = (self.pos + 1) % FFT_SIZE;
+        self.since_fft += 1;
+        // spread the work: one axis per sample after the hop
+        if self.since_fft >= HOP {
+            self.analyze(self.axis);
+            self.retune();
+            self.axis += 1;
+            if self.axis == 3 {
+                self.axis = 0;
+                self.since_fft = 0;
+            }
+        }
+
+        let mut out = *gyro;
+        for axis in 0..3 {
+            for notch in self.notches[axis].iter_mut() {
+                out[axis] = notch.apply(out[axis]);
+            }
+        }
+        out
+    }
+
+    fn retune(&mut self) {
+        let count = self.config.count.min(MAX_PEAKS);
+        for axis in 0..3 {
+            for (i, notch) in self.notches[axis].iter_mut().enumerate() {
+                let center_hz =
+                    if i < count { self.peaks[axis][i] } else { 0. };
+                let config =
+                    filters::NotchConfig::new(center_hz, self.config.q);
+                notch.configure(&config, self.sample_hz);
+            }
+        }
+    }
+
+    fn analyze(&mut self, axis: usize) {
+        // oldest sample first
+        for i in 0..FFT_SIZE {
+            let sample = self.window[axis][(self.pos + i) % FFT_SIZE];
+            self.re[i] = sample * self.hann[i];
+            self.im[i] = 0.0;
+        }
+        self.fft();
+
+        let bin_hz = self.sample_hz / FFT_SIZE as f32;
+        let mut power = [0.0; HALF];
+        for k in 1..HALF {
+            power[k] = self.re[k] * self.re[k] + self.im[k] * self.im[k];
+        }
+        // median is a noise floor not skewed by the peaks themselves
+        let mut sorted = power;
+        sorted[1..].sort_unstable_by(|a, b| {
+            a.partial_cmp(b).unwrap_or(core::cmp::Ordering::Equal)
+        });
+        let floor = sorted[HALF / 2];
+
+        // strongest local maxima, by power
+        let count = self.config.count.min(MAX_PEAKS);
+        let mut found = [(0.0f32, 0.0f32); MAX_PEAKS];
+        let min_bin = ((self.config.min_hz / bin_hz) as usize).max(2);
+        for k in min_bin..HALF - 1 {
+            let p = power[k];
+            let is_peak = p > power[k - 1] && p >= power[k + 1];
+            if !is_peak || p < floor * PEAK_THRESHOLD {
+                continue;
+            }
+            if let Some(slot) = (0..count).find(|&i| p > found[i].0) {
+                for i in (slot + 1..count).rev() {
+                    found[i] = found[i - 1];
+                }
+                // parabolic interpolation between neighbouring bins
+                let (l, r) = (power[k - 1], power[k + 1]);
+                let denom = l - 2. * p + r;
+                let delta = if denom != 0. {
+                    0.5 * (l - r) / denom
+                } else {
+                    0.
+                };
+                found[slot] = (p, (k as f32 + delta) * bin_hz);
+            }
+        }
+
+        let mut freqs = [0.0; MAX_PEAKS];
+        for i in 0..count {
+            freqs[i] = found[i].1;
+        }
+        self.track(axis, &freqs[..count], bin_hz);
+    }
+
+    // Found frequencies, strongest first and 0 past the last one, go to
+    // nearest tracked slots, so a notch never jumps to another peak;
+    // new ones take free slots.
+    fn track(&mut self, axis: usize, freqs: &[f32], bin_hz: f32) {
+        let peaks = &mut self.peaks[axis];
+        let misses = &mut self.misses[axis];
+        let mut matched = [false; MAX_PEAKS];
+        let mut new = [0.0; MAX_PEAKS];
+        for (j, &hz) in freqs.iter().enumerate() {
+            if hz <= 0. {
+                continue;
+            }
+            let mut nearest: Option<(usize, f32)> = None;
+            for i in 0..freqs.len() {
+                let tracked = peaks[i];
+                let distance = fabsf(hz - tracked);
+                let near = distance <= (tracked * MATCH_RATIO).max(bin_hz);
+                let closer = nearest.map_or(true, |(_, d)| distance < d);
+                if !matched[i] && tracked > 0. && near && closer {
+                    nearest = Some((i, distance));
+                }
+            }
+            match nearest {
+                Some((i, _)) => {
+                    matched[i] = true;
+                    peaks[i] += TRACK_K * (hz - peaks[i]);
+                }
+                None => new[j] = hz,
+            }
+        }
+        for &hz in new.iter().filter(|&&hz| hz > 0.) {
+            let free =
+                (0..freqs.len()).find(|&i| !matched[i] && peaks[i] <= 0.);
+            if let Some(i) = free {
+                matched[i] = true;
+                peaks[i] = hz;
+            }
+        }
+        for i in 0..freqs.len() {
+            if matched[i] {
+                misses[i] = 0;
+                continue;
+            }
+            // vibration that is gone stops being cut
+            misses[i] = misses[i].saturating_add(1);
+            if misses[i] >= MAX_MISSES {
+                peaks[i] = 0.;
+            }
+        }
+    }
+
+    // in-place iterative radix-2
+    fn fft(&mut self) {
+        let mut j = 0;
+        for i in 1..FFT_SIZE {
+            let mut bit = FFT_SIZE >> 1;
+            while j & bit != 0 {
+                j ^= bit;
+                bit >>= 1;
+            }
+            j |= bit;
+            if i < j {
+                self.re.swap(i, j);
+                self.im.swap(i, j);
+            }
+        }
+
+        let mut len = 2;
+        while len <= FFT_SIZE {
+            let step = FFT_SIZE / len;
+            for start in (0..FFT_SIZE).step_by(len) {
+                for k in 0..len / 2 {
+                    let (wr, wi) = self.twiddle[k * step];
+                    let a = start + k;
+                    let b = a + len / 2;
+                    let tr = self.re[b] * wr - self.im[b] * wi;
+                    let ti = self.re[b] * wi + self.im[b] * wr;
+                    self.re[b] = self.re[a] - tr;
+                    self.im[b] = self.im[a] - ti;
+                    self.re[a] += tr;
+                    self.im[a] += ti;
+                }
+            }
+            len <<= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BIN_HZ: f32 = 1000. / FFT_SIZE as f32;
+
+    fn tracking(peaks: [f32; 2]) -> DynNotch {
+        let mut notch = DynNotch::new();
+        let config = Config {
+            count: 2,
+            ..Config::new()
+        };
+        notch.configure(&config, 1000.);
+        notch.peaks[0][..2].copy_from_slice(&peaks);
+        notch
+    }
+
+    #[test]
+    fn peaks_stay_in_their_slots() {
+        let mut notch = tracking([200., 100.]);
+        notch.track(0, &[104., 196.], BIN_HZ);
+        assert_eq!(notch.peaks[0][..2], [198., 102.]);
+    }
+
+    #[test]
+    fn lost_peak_misses_in_its_own_slot() {
+        let mut notch = tracking([200., 100.]);
+        for _ in 0..MAX_MISSES - 1 {
+            notch.track(0, &[100., 0.], BIN_HZ);
+        }
+        assert_eq!(notch.peaks[0][..2], [200., 100.]);
+        notch.track(0, &[100., 0.], BIN_HZ);
+        assert_eq!(notch.peaks[0][..2], [0., 100.]);
+        assert_eq!(notch.misses[0][1], 0);
+    }
+
+    #[test]
+    fn new_peak_takes_free_slot() {
+        let mut notch = tracking([0., 100.]);
+        notch.track(0, &[300., 100.], BIN_HZ);
+        assert_eq!(notch.peaks[0][..2], [300., 100.]);
+        // too far from 300Hz to be the same vibration
+        let mut notch = tracking([300., 100.]);
+        notch.track(0, &[500., 100.], BIN_HZ);
+        assert_eq!(notch.peaks[0][..2], [300., 100.]);
+        assert_eq!(notch.misses[0][..2], [1, 0]);
+    }
+}
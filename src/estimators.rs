@@ -0,0 +1,225 @@
+// Attitude estimators, selectable by cargo feature (default) and at
+// runtime, so they can be compared on the same aircraft.
+use libm::{atan2f, sqrtf};
+
+use crate::kalman;
+use crate::prelude::*;
+use crate::quaternion::Quaternion;
+
+mod dcm;
+pub mod ekf;
+mod madgwick;
+mod mahony;
+
+pub use dcm::Dcm;
+pub use ekf::Ekf;
+pub use madgwick::Madgwick;
+pub use mahony::Mahony;
+
+#[derive(Copy, Clone)]
+pub struct Attitude {
+    pub q: Quaternion,
+    pub ypr: EulerAngles,
+    // rad/s, as estimated by filter; zero if it doesn't track them
+    pub gyro_biases: [f32; 3],
+}
+
+pub trait AttitudeEstimator {
+    // gyro in rad/s, accel in m/s^2, mag calibrated in any units
+    fn update(
+        &mut self,
+        gyro: &[f32; 3],
+        accel: &[f32; 3],
+        mag: Option<&[f32; 3]>,
+        dt: f32,
+    ) -> Attitude;
+
+    // heading is taken from magnetometer when it is given
+    fn uses_mag(&self) -> bool;
+
+    // Weight of accel correction, (0, 1]; lowered when accel is
+    // disturbed by vibration.
+    fn set_accel_trust(&mut self, trust: f32);
+
+    fn reset(&mut self);
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Kind {
+    Dcm,
+    Mahony,
+    Madgwick,
+    Ekf,
+}
+
+impl Kind {
+    #[cfg(estimator = "estimator_dcm")]
+    pub const DEFAULT: Kind = Kind::Dcm;
+    #[cfg(estimator = "estimator_mahony")]
+    pub const DEFAULT: Kind = Kind::Mahony;
+    #[cfg(estimator = "estimator_madgwick")]
+    pub const DEFAULT: Kind = Kind::Madgwick;
+    #[cfg(estimator = "estimator_ekf")]
+    pub const DEFAULT: Kind = Kind::Ekf;
+
+    #[inline]
+    pub fn from_i32(v: i32) -> Option<Self> {
+        match v {
+            0 => Some(Kind::Dcm),
+            1 => Some(Kind::Mahony),
+            2 => Some(Kind::Madgwick),
+            3 => Some(Kind::Ekf),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Config {
+    pub kind: Kind,
+    // Mahony proportional and integral gains
+    pub kp: f32,
+    pub ki: f32,
+    // Madgwick gradient descent step
+    pub beta: f32,
+    pub ekf: ekf::Config,
+    // per-axis roll and pitch filter, runs alongside for comparison
+    pub kalman: kalman::Config,
+}
+
+impl Config {
+    #[inline]
+    pub const fn new() -> Self {
+        Config {
+            kind: Kind::DEFAULT,
+            kp: 1.0,
+            ki: 0.0,
+            beta: 0.1,
+            ekf: ekf::Config::new(),
+            kalman: kalman::Config::new(),
+        }
+    }
+}
+
+// Estimator of runtime selectable kind
+pub enum Estimator {
+    Dcm(Dcm),
+    Mahony(Mahony),
+    Madgwick(Madgwick),
+    Ekf(Ekf),
+}
+
+impl Estimator {
+    pub fn new(config: &Config) -> Self {
+        match config.kind {
+            Kind::Dcm => Estimator::Dcm(Dcm::new()),
+            Kind::Mahony => {
+                Estimator::Mahony(Mahony::new(config.kp, config.ki))
+            }
+            Kind::Madgwick => Estimator::Madgwick(Madgwick::new(config.beta)),
+            Kind::Ekf => Estimator::Ekf(Ekf::new(&config.ekf)),
+        }
+    }
+
+    // cheap if kind didn't change; new kind starts from scratch
+    pub fn configure(&mut self, config: &Config) {
+        match (config.kind, &mut *self) {
+            (Kind::Dcm, Estimator::Dcm(_)) => {}
+            (Kind::Mahony, Estimator::Mahony(f)) => {
+                f.set_gains(config.kp, config.ki)
+            }
+            (Kind::Madgwick, Estimator::Madgwick(f)) => f.set_beta(config.beta),
+            (Kind::Ekf, Estimator::Ekf(f)) => f.set_config(&config.ekf),
+            _ => *self = Estimator::new(config),
+        }
+    }
+
+    // covariance diagonal for health monitoring, zero if not tracked
+    #[inline]
+    pub fn variances(&self) -> [f32; ekf::MAX_STATES] {
+        match self {
+            Estimator::Ekf(f) => f.variances(),
+            _ => [0.0; ekf::MAX_STATES],
+        }
+    }
+}
+
+impl AttitudeEstimator for Estimator {
+    #[inline]
+    fn update(
+        &mut self,
+        gyro: &[f32; 3],
+        accel: &[f32; 3],
+        mag: Option<&[f32; 3]>,
+        dt: f32,
+    ) -> Attitude {
+        match self {
+            Estimator::Dcm(f) => f.update(gyro, accel, mag, dt),
+            Estimator::Mahony(f) => f.update(gyro, accel, mag, dt),
+            Estimator::Madgwick(f) => f.update(gyro, accel, mag, dt),
+            Estimator::Ekf(f) => f.update(gyro, accel, mag, dt),
+        }
+    }
+
+    #[inline]
+    fn uses_mag(&self) -> bool {
+        match self {
+            Estimator::Dcm(f) => f.uses_mag(),
+            Estimator::Mahony(f) => f.uses_mag(),
+            Estimator::Madgwick(f) => f.uses_mag(),
+            Estimator::Ekf(f) => f.uses_mag(),
+        }
+    }
+
+    #[inline]
+    fn set_accel_trust(&mut self, trust: f32) {
+        match self {
+            Estimator::Dcm(f) => f.set_accel_trust(trust),
+            Estimator::Mahony(f) => f.set_accel_trust(trust),
+            Estimator::Madgwick(f) => f.set_accel_trust(trust),
+            Estimator::Ekf(f) => f.set_accel_trust(trust),
+        }
+    }
+
+    #[inline]
+    fn reset(&mut self) {
+        match self {
+            Estimator::Dcm(f) => f.reset(),
+            Estimator::Mahony(f) => f.reset(),
+            Estimator::Madgwick(f) => f.reset(),
+            Estimator::Ekf(f) => f.reset(),
+        }
+    }
+}
+
+// Helpers for quaternion based filters
+
+fn normalized(v: &[f32; 3]) -> Option<[f32; 3]> {
+    let norm = sqrtf(v[0] * v[0] + v[1] * v[1] + v[2] * v[2]);
+    if norm > 0. {
+        Some([v[0] / norm, v[1] / norm, v[2] / norm])
+    } else {
+        None
+    }
+}
+
+fn cross(a: &[f32; 3], b: &[f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+// Attitude from gravity and, if given, magnetic field; used to start
+// quaternion filters close to the truth instead of converging slowly.
+fn initial_attitude(accel: &[f32; 3], mag: Option<&[f32; 3]>) -> Quaternion {
+    let roll = atan2f(accel[1], accel[2]);
+    let pitch =
+        atan2f(-accel[0], sqrtf(accel[1] * accel[1] + accel[2] * accel[2]));
+    let yaw = match mag {
+        Some(m) => crate::mag::heading(m, roll, pitch),
+        None => 0.,
+    };
+    Quaternion::from_euler(yaw, pitch, roll)
+}
@@ -0,0 +1,84 @@
+use dcmimu::DCMIMU;
+use libm::sqrtf;
+
+use super::{Attitude, AttitudeEstimator};
+use crate::quaternion::Quaternion;
+
+// Direction cosine matrix filter from `dcmimu`; its gains are fixed
+// by the crate and magnetometer is not used.
+pub struct Dcm {
+    dcmimu: DCMIMU,
+    accel_trust: f32,
+    // gravity direction in body frame after previous update
+    down: Option<[f32; 3]>,
+}
+
+impl Dcm {
+    #[inline]
+    pub fn new() -> Self {
+        Dcm {
+            dcmimu: DCMIMU::new(),
+            accel_trust: 1.0,
+            down: None,
+        }
+    }
+
+    // Gains can't be lowered, so accel is pulled toward gravity the
+    // filter expects by `1 - trust` and it corrects that much less.
+    fn blended(&self, accel: &[f32; 3]) -> [f32; 3] {
+        let down = match self.down {
+            Some(down) if self.accel_trust < 1. => down,
+            _ => return *accel,
+        };
+        let norm = sqrtf(
+            accel[0] * accel[0] + accel[1] * accel[1] + accel[2] * accel[2],
+        );
+        let k = 1. - self.accel_trust;
+        let mut out = *accel;
+        for i in 0..3 {
+            out[i] += k * (norm * down[i] - accel[i]);
+        }
+        out
+    }
+}
+
+impl AttitudeEstimator for Dcm {
+    #[inline]
+    fn update(
+        &mut self,
+        gyro: &[f32; 3],
+        accel: &[f32; 3],
+        _mag: Option<&[f32; 3]>,
+        dt: f32,
+    ) -> Attitude {
+        let accel = self.blended(accel);
+        let (ypr, biases) = self.dcmimu.update(
+            (gyro[0], gyro[1], gyro[2]),
+            (accel[0], accel[1], accel[2]),
+            dt,
+        );
+        let q = Quaternion::from_euler(ypr.yaw, ypr.pitch, ypr.roll);
+        self.down = Some(q.to_body(&[0., 0., 1.]));
+        Attitude {
+            q,
+            ypr,
+            gyro_biases: [biases.x, biases.y, biases.z],
+        }
+    }
+
+    #[inline]
+    fn uses_mag(&self) -> bool {
+        false
+    }
+
+    #[inline]
+    fn set_accel_trust(&mut self, trust: f32) {
+        self.accel_trust = trust;
+    }
+
+    #[inline]
+    fn reset(&mut self) {
+        self.dcmimu = DCMIMU::new();
+        self.down = None;
+    }
+}
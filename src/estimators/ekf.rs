@@ -0,0 +1,376 @@
+use super::*;
+
+// Multiplicative extended Kalman filter. Nominal state is attitude
+// quaternion and gyro biases (plus earth magnetic field when enabled);
+// the filter runs on small error state: rotation vector, bias error
+// and field error. At most 9 states, so covariance is 324 bytes.
+pub const MAX_STATES: usize = 9;
+
+type Matrix = [[f32; MAX_STATES]; MAX_STATES];
+// n x 3, one vector measurement
+type Gain = [[f32; 3]; MAX_STATES];
+
+// initial standard deviations: rad, rad/s, normalized field
+const INITIAL_ANGLE_STD: f32 = 0.1;
+const INITIAL_BIAS_STD: f32 = 0.01;
+const INITIAL_FIELD_STD: f32 = 0.1;
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Config {
+    // 9 states: earth magnetic field is estimated too
+    pub mag_states: bool,
+    // rad/s
+    pub gyro_noise: f32,
+    // bias random walk, rad/s^2
+    pub bias_noise: f32,
+    // field random walk, normalized units per s
+    pub field_noise: f32,
+    // measurement noise of normalized vectors
+    pub accel_noise: f32,
+    pub mag_noise: f32,
+}
+
+impl Config {
+    #[inline]
+    pub const fn new() -> Self {
+        Config {
+            mag_states: false,
+            gyro_noise: 0.01,
+            bias_noise: 0.0001,
+            field_noise: 0.0001,
+            accel_noise: 0.1,
+            mag_noise: 0.2,
+        }
+    }
+
+    #[inline]
+    fn states(&self) -> usize {
+        if self.mag_states {
+            9
+        } else {
+            6
+        }
+    }
+}
+
+pub struct Ekf {
+    config: Config,
+    n: usize,
+    q: Quaternion,
+    bias: [f32; 3],
+    // earth frame, normalized
+    field: [f32; 3],
+    p: Matrix,
+    // accel noise is divided by it
+    accel_trust: f32,
+    initialized: bool,
+}
+
+impl Ekf {
+    pub fn new(config: &Config) -> Self {
+        let mut ekf = Ekf {
+            config: *config,
+            n: config.states(),
+            q: Quaternion::identity(),
+            bias: [0.0; 3],
+            field: [0.0; 3],
+            p: [[0.0; MAX_STATES]; MAX_STATES],
+            accel_trust: 1.0,
+            initialized: false,
+        };
+        ekf.reset_covariance();
+        ekf
+    }
+
+    // noises apply immediately, state count change restarts filter
+    pub fn set_config(&mut self, config: &Config) {
+        if self.config.states() != config.states() {
+            *self = Ekf::new(config);
+        } else {
+            self.config = *config;
+        }
+    }
+
+    #[inline]
+    pub fn covariance(&self) -> &Matrix {
+        &self.p
+    }
+
+    // diagonal of covariance; unused states are zero
+    pub fn variances(&self) -> [f32; MAX_STATES] {
+        let mut v = [0.0; MAX_STATES];
+        for i in 0..self.n {
+            v[i] = self.p[i][i];
+        }
+        v
+    }
+
+    fn reset_covariance(&mut self) {
+        self.p = [[0.0; MAX_STATES]; MAX_STATES];
+        for i in 0..self.n {
+            let std = match i {
+                0..=2 => INITIAL_ANGLE_STD,
+                3..=5 => INITIAL_BIAS_STD,
+                _ => INITIAL_FIELD_STD,
+            };
+            self.p[i][i] = std * std;
+        }
+    }
+
+    fn predict(&mut self, rates: &[f32; 3], dt: f32) {
+        self.q = self.q.integrate(rates, dt);
+
+        // F = I + A * dt, A = [[-[w x], -I, 0], [0, 0, 0], [0, 0, 0]]
+        let n = self.n;
+        let mut f = [[0.0; MAX_STATES]; MAX_STATES];
+        for i in 0..n {
+            f[i][i] = 1.;
+        }
+        let w = skew(rates);
+        for i in 0..3 {
+            for j in 0..3 {
+                f[i][j] -= w[i][j] * dt;
+            }
+            f[i][3 + i] = -dt;
+        }
+
+        // P = F P F^T + Q
+        let mut fp = [[0.0; MAX_STATES]; MAX_STATES];
+        for i in 0..n {
+            for j in 0..n {
+                let mut acc = 0.;
+                for k in 0..n {
+                    acc += f[i][k] * self.p[k][j];
+                }
+                fp[i][j] = acc;
+            }
+        }
+        for i in 0..n {
+            for j in 0..n {
+                let mut acc = 0.;
+                for k in 0..n {
+                    acc += fp[i][k] * f[j][k];
+                }
+                self.p[i][j] = acc;
+            }
+        }
+        let c = &self.config;
+        for i in 0..n {
+            let noise = match i {
+                0..=2 => c.gyro_noise,
+                3..=5 => c.bias_noise,
+                _ => c.field_noise,
+            };
+            self.p[i][i] += noise * noise * dt;
+        }
+    }
+
+    // Vector measurement z against prediction h, h_x is measurement
+    // jacobian over error state (3 x n). Standard EKF update, then
+    // error is folded into nominal state.
+    fn correct(
+        &mut self,
+        z: &[f32; 3],
+        h: &[f32; 3],
+        h_x: &[[f32; MAX_STATES]; 3],
+        noise: f32,
+    ) {
+        let n = self.n;
+        // P H^T
+        let mut pht: Gain = [[0.0; 3]; MAX_STATES];
+        for i in 0..n {
+            for j in 0..3 {
+                let mut acc = 0.;
+                for k in 0..n {
+                    acc += self.p[i][k] * h_x[j][k];
+                }
+                pht[i][j] = acc;
+            }
+        }
+        // S = H P H^T + R
+        let mut s = [[0.0; 3]; 3];
+        for i in 0..3 {
+            for j in 0..3 {
+                let mut acc = 0.;
+                for k in 0..n {
+                    acc += h_x[i][k] * pht[k][j];
+                }
+                s[i][j] = acc;
+            }
+            s[i][i] += noise * noise;
+        }
+        let s_inv = match invert3(&s) {
+            Some(s_inv) => s_inv,
+            None => return,
+        };
+        // K = P H^T S^-1
+        let mut k: Gain = [[0.0; 3]; MAX_STATES];
+        for i in 0..n {
+            for j in 0..3 {
+                k[i][j] = (0..3).map(|l| pht[i][l] * s_inv[l][j]).sum();
+            }
+        }
+
+        let y = [z[0] - h[0], z[1] - h[1], z[2] - h[2]];
+        let mut dx = [0.0; MAX_STATES];
+        for i in 0..n {
+            dx[i] = (0..3).map(|j| k[i][j] * y[j]).sum();
+        }
+
+        // P = P - K (H P), H P is transpose of P H^T
+        for i in 0..n {
+            for j in 0..n {
+                let kh: f32 = (0..3).map(|l| k[i][l] * pht[j][l]).sum();
+                self.p[i][j] -= kh;
+            }
+        }
+        // keep symmetric
+        for i in 0..n {
+            for j in i + 1..n {
+                let v = (self.p[i][j] + self.p[j][i]) / 2.;
+                self.p[i][j] = v;
+                self.p[j][i] = v;
+            }
+        }
+
+        let dq = Quaternion::new(1., dx[0] / 2., dx[1] / 2., dx[2] / 2.);
+        self.q = (self.q * dq)
+            .normalized()
+            .unwrap_or_else(Quaternion::identity);
+        for i in 0..3 {
+            self.bias[i] += dx[3 + i];
+        }
+        if n == MAX_STATES {
+            for i in 0..3 {
+                self.field[i] += dx[6 + i];
+            }
+        }
+    }
+
+    fn correct_gravity(&mut self, a: &[f32; 3]) {
+        let h = self.q.to_body(&[0., 0., 1.]);
+        let mut h_x = [[0.0; MAX_STATES]; 3];
+        let hs = skew(&h);
+        for i in 0..3 {
+            for j in 0..3 {
+                h_x[i][j] = hs[i][j];
+            }
+        }
+        let noise = self.config.accel_noise / self.accel_trust;
+        self.correct(a, &h, &h_x, noise);
+    }
+
+    fn correct_field(&mut self, m: &[f32; 3]) {
+        let reference = if self.n == MAX_STATES {
+            self.field
+        } else {
+            // no field states: measured field in earth frame with
+            // horizontal part folded onto north
+            let e = self.q.to_earth(m);
+            [sqrtf(e[0] * e[0] + e[1] * e[1]), 0., e[2]]
+        };
+        let h = self.q.to_body(&reference);
+        let mut h_x = [[0.0; MAX_STATES]; 3];
+        let hs = skew(&h);
+        let r = self.q.to_matrix();
+        for i in 0..3 {
+            for j in 0..3 {
+                h_x[i][j] = hs[i][j];
+                if self.n == MAX_STATES {
+                    // d(R^T f)/df
+                    h_x[i][6 + j] = r[j][i];
+                }
+            }
+        }
+        self.correct(m, &h, &h_x, self.config.mag_noise);
+    }
+}
+
+impl AttitudeEstimator for Ekf {
+    fn update(
+        &mut self,
+        gyro: &[f32; 3],
+        accel: &[f32; 3],
+        mag: Option<&[f32; 3]>,
+        dt: f32,
+    ) -> Attitude {
+        let a = normalized(accel);
+        let m = mag.and_then(normalized);
+        if !self.initialized {
+            if let Some(ref a) = a {
+                self.q = initial_attitude(a, m.as_ref());
+                if let Some(ref m) = m {
+                    self.field = self.q.to_earth(m);
+                }
+                self.initialized = true;
+            }
+        }
+
+        let rates = [
+            gyro[0] - self.bias[0],
+            gyro[1] - self.bias[1],
+            gyro[2] - self.bias[2],
+        ];
+        self.predict(&rates, dt);
+        if let Some(ref a) = a {
+            self.correct_gravity(a);
+        }
+        if let Some(ref m) = m {
+            self.correct_field(m);
+        }
+
+        Attitude {
+            q: self.q,
+            ypr: self.q.to_euler(),
+            gyro_biases: self.bias,
+        }
+    }
+
+    #[inline]
+    fn uses_mag(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn set_accel_trust(&mut self, trust: f32) {
+        self.accel_trust = trust;
+    }
+
+    #[inline]
+    fn reset(&mut self) {
+        *self = Ekf::new(&self.config);
+    }
+}
+
+// [v x], so that skew(v) * u == v x u
+fn skew(v: &[f32; 3]) -> [[f32; 3]; 3] {
+    [[0., -v[2], v[1]], [v[2], 0., -v[0]], [-v[1], v[0], 0.]]
+}
+
+fn invert3(m: &[[f32; 3]; 3]) -> Option<[[f32; 3]; 3]> {
+    let c00 = m[1][1] * m[2][2] - m[1][2] * m[2][1];
+    let c01 = m[1][2] * m[2][0] - m[1][0] * m[2][2];
+    let c02 = m[1][0] * m[2][1] - m[1][1] * m[2][0];
+    let det = m[0][0] * c00 + m[0][1] * c01 + m[0][2] * c02;
+    if det == 0. {
+        return None;
+    }
+    let inv = 1. / det;
+    Some([
+        [
+            c00 * inv,
+            (m[0][2] * m[2][1] - m[0][1] * m[2][2]) * inv,
+            (m[0][1] * m[1][2] - m[0][2] * m[1][1]) * inv,
+        ],
+        [
+            c01 * inv,
+            (m[0][0] * m[2][2] - m[0][2] * m[2][0]) * inv,
+            (m[0][2] * m[1][0] - m[0][0] * m[1][2]) * inv,
+        ],
+        [
+            c02 * inv,
+            (m[0][1] * m[2][0] - m[0][0] * m[2][1]) * inv,
+            (m[0][0] * m[1][1] - m[0][1] * m[1][0]) * inv,
+        ],
+    ])
+}
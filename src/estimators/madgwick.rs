@@ -0,0 +1,113 @@
+use super::*;
+
+// Madgwick gradient descent filter: gyro integration corrected by one
+// normalised gradient step of the gravity (and field) alignment error,
+// scaled with beta.
+pub struct Madgwick {
+    beta: f32,
+    // scales beta; gravity and field share one gradient step
+    accel_trust: f32,
+    q: Quaternion,
+    initialized: bool,
+}
+
+impl Madgwick {
+    #[inline]
+    pub const fn new(beta: f32) -> Self {
+        Madgwick {
+            beta,
+            accel_trust: 1.0,
+            q: Quaternion::identity(),
+            initialized: false,
+        }
+    }
+
+    #[inline]
+    pub fn set_beta(&mut self, beta: f32) {
+        self.beta = beta;
+    }
+
+    // objective gradient, J^T * f
+    fn gradient(&self, a: &[f32; 3], m: Option<&[f32; 3]>) -> Quaternion {
+        let (q0, q1, q2, q3) = (self.q.w, self.q.x, self.q.y, self.q.z);
+        let v = self.q.to_body(&[0., 0., 1.]);
+        let f = [v[0] - a[0], v[1] - a[1], v[2] - a[2]];
+        let mut s = [
+            -2. * q2 * f[0] + 2. * q1 * f[1],
+            2. * q3 * f[0] + 2. * q0 * f[1] - 4. * q1 * f[2],
+            -2. * q0 * f[0] + 2. * q3 * f[1] - 4. * q2 * f[2],
+            2. * q1 * f[0] + 2. * q2 * f[1],
+        ];
+
+        if let Some(m) = m {
+            let h = self.q.to_earth(m);
+            let bx = sqrtf(h[0] * h[0] + h[1] * h[1]);
+            let bz = h[2];
+            let w = self.q.to_body(&[bx, 0., bz]);
+            let f = [w[0] - m[0], w[1] - m[1], w[2] - m[2]];
+            s[0] += -2. * bz * q2 * f[0]
+                + (-2. * bx * q3 + 2. * bz * q1) * f[1]
+                + 2. * bx * q2 * f[2];
+            s[1] += 2. * bz * q3 * f[0]
+                + (2. * bx * q2 + 2. * bz * q0) * f[1]
+                + (2. * bx * q3 - 4. * bz * q1) * f[2];
+            s[2] += (-4. * bx * q2 - 2. * bz * q0) * f[0]
+                + (2. * bx * q1 + 2. * bz * q3) * f[1]
+                + (2. * bx * q0 - 4. * bz * q2) * f[2];
+            s[3] += (-4. * bx * q3 + 2. * bz * q1) * f[0]
+                + (-2. * bx * q0 + 2. * bz * q2) * f[1]
+                + 2. * bx * q1 * f[2];
+        }
+        Quaternion::new(s[0], s[1], s[2], s[3])
+    }
+}
+
+impl AttitudeEstimator for Madgwick {
+    fn update(
+        &mut self,
+        gyro: &[f32; 3],
+        accel: &[f32; 3],
+        mag: Option<&[f32; 3]>,
+        dt: f32,
+    ) -> Attitude {
+        let a = normalized(accel);
+        let m = mag.and_then(normalized);
+        if !self.initialized {
+            if let Some(ref a) = a {
+                self.q = initial_attitude(a, m.as_ref());
+                self.initialized = true;
+            }
+        }
+
+        let mut dq = self.q.derivative(gyro);
+        if let Some(a) = a {
+            let s = self.gradient(&a, m.as_ref());
+            if let Some(s) = s.normalized() {
+                dq = dq + s.scaled(-self.beta * self.accel_trust);
+            }
+        }
+        self.q = (self.q + dq.scaled(dt))
+            .normalized()
+            .unwrap_or_else(Quaternion::identity);
+        Attitude {
+            q: self.q,
+            ypr: self.q.to_euler(),
+            gyro_biases: [0.0; 3],
+        }
+    }
+
+    #[inline]
+    fn uses_mag(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn set_accel_trust(&mut self, trust: f32) {
+        self.accel_trust = trust;
+    }
+
+    #[inline]
+    fn reset(&mut self) {
+        *self = Madgwick::new(self.beta);
+    }
+}
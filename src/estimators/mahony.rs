@@ -0,0 +1,110 @@
+use super::*;
+
+// Mahony nonlinear complementary filter: PI feedback of the error
+// between measured and predicted gravity (and field) directions;
+// integral part tracks gyro biases.
+pub struct Mahony {
+    kp: f32,
+    ki: f32,
+    q: Quaternion,
+    // added to gyro, rad/s
+    integral: [f32; 3],
+    // scales gravity error
+    accel_trust: f32,
+    initialized: bool,
+}
+
+impl Mahony {
+    #[inline]
+    pub const fn new(kp: f32, ki: f32) -> Self {
+        Mahony {
+            kp,
+            ki,
+            q: Quaternion::identity(),
+            integral: [0.0; 3],
+            accel_trust: 1.0,
+            initialized: false,
+        }
+    }
+
+    #[inline]
+    pub fn set_gains(&mut self, kp: f32, ki: f32) {
+        self.kp = kp;
+        if ki <= 0. {
+            self.integral = [0.0; 3];
+        }
+        self.ki = ki;
+    }
+}
+
+impl AttitudeEstimator for Mahony {
+    fn update(
+        &mut self,
+        gyro: &[f32; 3],
+        accel: &[f32; 3],
+        mag: Option<&[f32; 3]>,
+        dt: f32,
+    ) -> Attitude {
+        let a = normalized(accel);
+        let m = mag.and_then(normalized);
+        if !self.initialized {
+            if let Some(ref a) = a {
+                self.q = initial_attitude(a, m.as_ref());
+                self.initialized = true;
+            }
+        }
+
+        let mut g = *gyro;
+        if let Some(a) = a {
+            let v = self.q.to_body(&[0., 0., 1.]);
+            let mut e = cross(&a, &v);
+            for i in 0..3 {
+                e[i] *= self.accel_trust;
+            }
+            if let Some(m) = m {
+                // reference field: measured one in earth frame, with
+                // horizontal part folded onto north
+                let h = self.q.to_earth(&m);
+                let b = [sqrtf(h[0] * h[0] + h[1] * h[1]), 0., h[2]];
+                let w = self.q.to_body(&b);
+                let em = cross(&m, &w);
+                for i in 0..3 {
+                    e[i] += em[i];
+                }
+            }
+            for i in 0..3 {
+                if self.ki > 0. {
+                    self.integral[i] += self.ki * e[i] * dt;
+                    g[i] += self.integral[i];
+                }
+                g[i] += self.kp * e[i];
+            }
+        }
+
+        self.q = self.q.integrate(&g, dt);
+        Attitude {
+            q: self.q,
+            ypr: self.q.to_euler(),
+            gyro_biases: [
+                -self.integral[0],
+                -self.integral[1],
+                -self.integral[2],
+            ],
+        }
+    }
+
+    #[inline]
+    fn uses_mag(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn set_accel_trust(&mut self, trust: f32) {
+        self.accel_trust = trust;
+    }
+
+    #[inline]
+    fn reset(&mut self) {
+        *self = Mahony::new(self.kp, self.ki);
+    }
+}
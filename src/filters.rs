@@ -0,0 +1,509 @@
+use core::f32::consts::PI;
+use libm::{cosf, sinf};
+
+use crate::dyn_notch;
+
+pub trait Filter {
+    fn apply(&mut self, x: f32) -> f32;
+    fn reset(&mut self);
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Kind {
+    None,
+    Pt1,
+    Biquad,
+    Average,
+}
+
+impl Kind {
+    #[inline]
+    pub fn from_i32(v: i32) -> Option<Self> {
+        match v {
+            0 => Some(Kind::None),
+            1 => Some(Kind::Pt1),
+            2 => Some(Kind::Biquad),
+            3 => Some(Kind::Average),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Config {
+    pub kind: Kind,
+    pub cutoff_hz: f32,
+}
+
+impl Config {
+    #[inline]
+    pub const fn new(kind: Kind, cutoff_hz: f32) -> Self {
+        Config { kind, cutoff_hz }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct NotchConfig {
+    // 0 disables notch
+    pub center_hz: f32,
+    pub q: f32,
+}
+
+impl NotchConfig {
+    #[inline]
+    pub const fn new(center_hz: f32, q: f32) -> Self {
+        NotchConfig { center_hz, q }
+    }
+}
+
+// Filters on the sensor path and in controllers
+#[derive(Copy, Clone)]
+pub struct Settings {
+    pub gyro: Config,
+    pub accel: Config,
+    pub notch: NotchConfig,
+    pub dyn_notch: dyn_notch::Config,
+    pub dterm: Config,
+}
+
+impl Settings {
+    #[inline]
+    pub const fn new() -> Self {
+        Settings {
+            gyro: Config::new(Kind::Pt1, 90.0),
+            accel: Config::new(Kind::Biquad, 20.0),
+            notch: NotchConfig::new(0.0, 3.0),
+            dyn_notch: dyn_notch::Config::new(),
+            dterm: Config::new(Kind::Pt1, 40.0),
+        }
+    }
+}
+
+// First order low-pass
+pub struct Pt1 {
+    k: f32,
+    y: f32,
+}
+
+impl Pt1 {
+    #[inline]
+    pub const fn new() -> Self {
+        Pt1 { k: 1.0, y: 0.0 }
+    }
+
+    pub fn configure(&mut self, cutoff_hz: f32, sample_hz: f32) {
+        let dt = 1. / sample_hz;
+        let rc = 1. / (2. * PI * cutoff_hz);
+        self.k = dt / (rc + dt);
+    }
+}
+
+impl Filter for Pt1 {
+    #[inline]
+    fn apply(&mut self, x: f32) -> f32 {
+        self.y += self.k * (x - self.y);
+        self.y
+    }
+
+    #[inline]
+    fn reset(&mut self) {
+        self.y = 0.0;
+    }
+}
+
+// Second order section, direct form I: state is past input and output
+// only, so coefficients can change without a step
+pub struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+// Butterworth
+const LOWPASS_Q: f32 = 0.707_106_77;
+
+impl Biquad {
+    #[inline]
+    pub const fn new() -> Self {
+        Biquad {
+            b0: 1.0,
+            b1: 0.0,
+            b2: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    // RBJ audio EQ cookbook low-pass, `cutoff_hz` below `sample_hz / 2`
+    pub fn lowpass(&mut self, cutoff_hz: f32, sample_hz: f32) {
+        let omega = 2. * PI * cutoff_hz / sample_hz;
+        let sn = sinf(omega);
+        let cs = cosf(omega);
+        let alpha = sn / (2. * LOWPASS_Q);
+        let a0 = 1. + alpha;
+
+        self.b1 = (1. - cs) / a0;
+        self.b0 = self.b1 / 2.;
+        self.b2 = self.b0;
+        self.a1 = -2. * cs / a0;
+        self.a2 = (1. - alpha) / a0;
+    }
+
+    // RBJ audio EQ cookbook notch
+    pub fn notch(&mut self, center_hz: f32, q: f32, sample_hz: f32) {
+        let omega = 2. * PI * center_hz / sample_hz;
+        let sn = sinf(omega);
+        let cs = cosf(omega);
+        let alpha = sn / (2. * q);
+        let a0 = 1. + alpha;
+
+        self.b0 = 1. / a0;
+        self.b1 = -2. * cs / a0;
+        self.b2 = self.b0;
+        self.a1 = self.b1;
+        self.a2 = (1. - alpha) / a0;
+    }
+}
+
+impl Filter for Biquad {
+    #[inline]
+    fn apply(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2
+            - self.a1 * self.y1
+            - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+
+    #[inline]
+    fn reset(&mut self) {
+        self.x1 = 0.0;
+        self.x2 = 0.0;
+        self.y1 = 0.0;
+        self.y2 = 0.0;
+    }
+}
+
+const MAX_WINDOW: usize = 16;
+// -3dB point of N sample average is about this part of sample rate / N
+const AVERAGE_CUTOFF: f32 = 0.443;
+
+pub struct MovingAverage {
+    window: [f32; MAX_WINDOW],
+    len: usize,
+    pos: usize,
+    sum: f32,
+}
+
+impl MovingAverage {
+    #[inline]
+    pub const fn new() -> Self {
+        MovingAverage {
+            window: [0.0; MAX_WINDOW],
+            len: 1,
+            pos: 0,
+            sum: 0.0,
+        }
+    }
+
+    // Window is clamped to [1, 16] samples. New one starts filled with
+    // current average, so output doesn't step.
+    pub fn configure(&mut self, len: usize) {
+        let len = crate::utils::clamp(len, 1, MAX_WINDOW);
+        if len != self.len {
+            let mean = self.sum / self.len as f32;
+            self.len = len;
+            self.window = [mean; MAX_WINDOW];
+            self.pos = 0;
+            self.sum = mean * len as f32;
+        }
+    }
+
+    // window closest to given -3dB cutoff
+    #[inline]
+    pub fn configure_cutoff(&mut self, cutoff_hz: f32, sample_hz: f32) {
+        let len = AVERAGE_CUTOFF * sample_hz / cutoff_hz + 0.5;
+        self.configure(len as usize);
+    }
+}
+
+impl Filter for MovingAverage {
+    fn apply(&mut self, x: f32) -> f32 {
+        self.sum += x - self.window[self.pos];
+        self.window[self.pos] = x;
+        self.pos = (self.pos + 1) % self.len;
+        self.sum / self.len as f32
+    }
+
+    fn reset(&mut self) {
+        self.window = [0.0; MAX_WINDOW];
+        self.pos = 0;
+        self.sum = 0.0;
+    }
+}
+
+enum Stage {
+    None,
+    Pt1(Pt1),
+    Biquad(Biquad),
+    Average(MovingAverage),
+}
+
+// Low-pass of runtime selectable kind
+pub struct LowPass {
+    config: Config,
+    sample_hz: f32,
+    stage: Stage,
+}
+
+impl LowPass {
+    #[inline]
+    pub const fn new() -> Self {
+        LowPass {
+            config: Config::new(Kind::None, 0.0),
+            sample_hz: 0.0,
+            stage: Stage::None,
+        }
+    }
+
+    // Cheap if nothing changed, so can be called on every sample. Filter
+    // state is kept unless kind changes, so output doesn't step.
+    pub fn configure(&mut self, config: &Config, sample_hz: f32) {
+        if self.config == *config && self.sample_hz == sample_hz {
+            return;
+        }
+        self.config = *config;
+        self.sample_hz = sample_hz;
+        let cutoff_hz = config.cutoff_hz;
+        // at or above Nyquist filter is unstable or does nothing
+        let valid =
+            cutoff_hz > 0. && sample_hz > 0. && cutoff_hz < sample_hz / 2.;
+        let kind = if valid { config.kind } else { Kind::None };
+        match (kind, &mut self.stage) {
+            (Kind::None, _) => self.stage = Stage::None,
+            (Kind::Pt1, Stage::Pt1(f)) => f.configure(cutoff_hz, sample_hz),
+            (Kind::Biquad, Stage::Biquad(f)) => f.lowpass(cutoff_hz, sample_hz),
+            (Kind::Average, Stage::Average(f)) => {
+                f.configure_cutoff(cutoff_hz, sample_hz)
+            }
+            (Kind::Pt1, _) => {
+                let mut pt1 = Pt1::new();
+                pt1.configure(cutoff_hz, sample_hz);
+                self.stage = Stage::Pt1(pt1);
+            }
+            (Kind::Biquad, _) => {
+                let mut biquad = Biquad::new();
+                biquad.lowpass(cutoff_hz, sample_hz);
+                self.stage = Stage::Biquad(biquad);
+            }
+            (Kind::Average, _) => {
+                let mut average = MovingAverage::new();
+                average.configure_cutoff(cutoff_hz, sample_hz);
+                self.stage = Stage::Average(average);
+            }
+        }
+    }
+}
+
+impl Filter for LowPass {
+    #[inline]
+    fn apply(&mut self, x: f32) -> f32 {
+        match self.stage {
+            Stage::None => x,
+            Stage::Pt1(ref mut f) => f.apply(x),
+            Stage::Biquad(ref mut f) => f.apply(x),
+            Stage::Average(ref mut f) => f.apply(x),
+        }
+    }
+
+    #[inline]
+    fn reset(&mut self) {
+        match self.stage {
+            Stage::None => {}
+            Stage::Pt1(ref mut f) => f.reset(),
+            Stage::Biquad(ref mut f) => f.reset(),
+            Stage::Average(ref mut f) => f.reset(),
+        }
+    }
+}
+
+pub struct Notch {
+    config: NotchConfig,
+    sample_hz: f32,
+    biquad: Option<Biquad>,
+}
+
+impl Notch {
+    #[inline]
+    pub const fn new() -> Self {
+        Notch {
+            config: NotchConfig::new(0.0, 0.0),
+            sample_hz: 0.0,
+            biquad: None,
+        }
+    }
+
+    // cheap if nothing changed; filter state is kept on retune
+    pub fn configure(&mut self, config: &NotchConfig, sample_hz: f32) {
+        if self.config == *config && self.sample_hz == sample_hz {
+            return;
+        }
+        self.config = *config;
+        self.sample_hz = sample_hz;
+        let valid = config.q > 0.
+            && config.center_hz > 0.
+            && config.center_hz < sample_hz / 2.;
+        if !valid {
+            self.biquad = None;
+            return;
+        }
+        let biquad = self.biquad.get_or_insert_with(Biquad::new);
+        biquad.notch(config.center_hz, config.q, sample_hz);
+    }
+}
+
+impl Filter for Notch {
+    #[inline]
+    fn apply(&mut self, x: f32) -> f32 {
+        match self.biquad {
+            Some(ref mut f) => f.apply(x),
+            None => x,
+        }
+    }
+
+    #[inline]
+    fn reset(&mut self) {
+        if let Some(ref mut f) = self.biquad {
+            f.reset();
+        }
+    }
+}
+
+// Same filter on each of three axes
+pub struct Vector<F> {
+    pub axes: [F; 3],
+}
+
+impl<F> Vector<F> {
+    #[inline]
+    pub const fn new(x: F, y: F, z: F) -> Self {
+        Vector { axes: [x, y, z] }
+    }
+
+    #[inline]
+    pub fn apply(&mut self, v: &[f32; 3]) -> [f32; 3]
+    where
+        F: Filter,
+    {
+        [
+            self.axes[0].apply(v[0]),
+            self.axes[1].apply(v[1]),
+            self.axes[2].apply(v[2]),
+        ]
+    }
+
+    #[inline]
+    pub fn reset(&mut self)
+    where
+        F: Filter,
+    {
+        for f in self.axes.iter_mut() {
+            f.reset();
+        }
+    }
+}
+
+impl Vector<LowPass> {
+    #[inline]
+    pub const fn lowpass() -> Self {
+        Vector::new(LowPass::new(), LowPass::new(), LowPass::new())
+    }
+
+    #[inline]
+    pub fn configure(&mut self, config: &Config, sample_hz: f32) {
+        for f in self.axes.iter_mut() {
+            f.configure(config, sample_hz);
+        }
+    }
+}
+
+impl Vector<Notch> {
+    #[inline]
+    pub const fn notch() -> Self {
+        Vector::new(Notch::new(), Notch::new(), Notch::new())
+    }
+
+    #[inline]
+    pub fn configure(&mut self, config: &NotchConfig, sample_hz: f32) {
+        for f in self.axes.iter_mut() {
+            f.configure(config, sample_hz);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [Kind; 3] = [Kind::Pt1, Kind::Biquad, Kind::Average];
+
+    fn settled(kind: Kind) -> LowPass {
+        let mut lpf = LowPass::new();
+        lpf.configure(&Config::new(kind, 100.), 1000.);
+        for _ in 0..1000 {
+            lpf.apply(1.);
+        }
+        lpf
+    }
+
+    #[test]
+    fn retune_keeps_state() {
+        for &kind in KINDS.iter() {
+            let mut lpf = settled(kind);
+            lpf.configure(&Config::new(kind, 50.), 1000.);
+            assert!((lpf.apply(1.) - 1.).abs() < 1e-3);
+            lpf.configure(&Config::new(kind, 50.), 500.);
+            assert!((lpf.apply(1.) - 1.).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn kind_change_starts_over() {
+        let mut lpf = settled(Kind::Pt1);
+        lpf.configure(&Config::new(Kind::Biquad, 100.), 1000.);
+        assert!(lpf.apply(1.) < 0.5);
+    }
+
+    #[test]
+    fn cutoff_at_nyquist_passes_through() {
+        for &kind in KINDS.iter() {
+            let mut lpf = settled(kind);
+            lpf.configure(&Config::new(kind, 500.), 1000.);
+            assert_eq!(lpf.apply(3.), 3.);
+        }
+    }
+
+    #[test]
+    fn average_window_from_cutoff() {
+        let mut average = MovingAverage::new();
+        average.configure_cutoff(100., 1000.);
+        assert_eq!(average.len, 4);
+        average.configure_cutoff(10., 1000.);
+        assert_eq!(average.len, MAX_WINDOW);
+        average.configure_cutoff(400., 1000.);
+        assert_eq!(average.len, 1);
+    }
+}
@@ -0,0 +1,218 @@
+// IMU health: bus errors, frozen or clipped readings and identity
+// checks. A failing step makes the sensor re-initialize; enough of them
+// in a row end in failsafe, which holds until cleared. Clipping is only
+// counted: hard manoeuvres saturate a healthy sensor too.
+use libm::fabsf;
+
+use crate::imu;
+
+// identical readings in a row; noise never repeats that long
+const STUCK_SAMPLES: u32 = 64;
+// readings at full scale in a row
+const SATURATED_SAMPLES: u32 = 64;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Failsafe {
+    // all motors stopped
+    MotorsOff,
+    // idle thrust, no corrections
+    Idle,
+}
+
+impl Failsafe {
+    #[inline]
+    pub fn from_i32(v: i32) -> Option<Self> {
+        match v {
+            0 => Some(Failsafe::MotorsOff),
+            1 => Some(Failsafe::Idle),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn thrust(&self, idle_thrust: f32) -> f32 {
+        match self {
+            Failsafe::MotorsOff => 0.,
+            Failsafe::Idle => idle_thrust,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Config {
+    // steps between WHO_AM_I checks, 0 disables them
+    pub check_period: u32,
+    // failing steps in a row before failsafe
+    pub max_failures: u32,
+    pub failsafe: Failsafe,
+}
+
+impl Config {
+    #[inline]
+    pub const fn new() -> Self {
+        Config {
+            check_period: 250,
+            max_failures: 20,
+            failsafe: Failsafe::MotorsOff,
+        }
+    }
+}
+
+// events since start, except `failures`
+#[derive(Copy, Clone)]
+pub struct Counters {
+    pub bus_errors: u32,
+    pub stuck: u32,
+    pub saturated: u32,
+    // WHO_AM_I answered with something else
+    pub bad_id: u32,
+    pub reinits: u32,
+    // failing steps in a row
+    pub failures: u32,
+    pub failsafe: bool,
+}
+
+impl Counters {
+    #[inline]
+    pub const fn new() -> Self {
+        Counters {
+            bus_errors: 0,
+            stuck: 0,
+            saturated: 0,
+            bad_id: 0,
+            reinits: 0,
+            failures: 0,
+            failsafe: false,
+        }
+    }
+
+    // bus,stuck,saturated,bad_id,reinits,failures,failsafe
+    #[inline]
+    pub fn results(&self) -> [f32; 7] {
+        [
+            self.bus_errors as f32,
+            self.stuck as f32,
+            self.saturated as f32,
+            self.bad_id as f32,
+            self.reinits as f32,
+            self.failures as f32,
+            self.failsafe as u8 as f32,
+        ]
+    }
+}
+
+pub struct Monitor {
+    counters: Counters,
+    // rad/s and m/s^2
+    gyro_limit: f32,
+    accel_limit: f32,
+    // accel then gyro
+    previous: [f32; 6],
+    same: u32,
+    clipped: u32,
+    steps: u32,
+    // something went wrong in current step
+    failed: bool,
+}
+
+impl Monitor {
+    #[inline]
+    pub fn new(config: &imu::Config) -> Self {
+        let mut monitor = Monitor {
+            counters: Counters::new(),
+            gyro_limit: 0.,
+            accel_limit: 0.,
+            previous: [0.; 6],
+            same: 0,
+            clipped: 0,
+            steps: 0,
+            failed: false,
+        };
+        monitor.configure_range(config);
+        monitor
+    }
+
+    #[inline]
+    pub fn configure_range(&mut self, config: &imu::Config) {
+        self.gyro_limit = imu::CLIPPED * config.gyro_full_scale();
+        self.accel_limit = imu::CLIPPED * config.accel_full_scale();
+    }
+
+    // Raw sample, before biases and alignment. Stuck sensor fails every
+    // step until it gets better.
+    pub fn check(&mut self, sample: &imu::Sample) {
+        let (a, g) = (&sample.accel, &sample.gyro);
+        let values = [a[0], a[1], a[2], g[0], g[1], g[2]];
+        if values == self.previous {
+            self.same = self.same.saturating_add(1);
+            if self.same == STUCK_SAMPLES {
+                self.counters.stuck = self.counters.stuck.wrapping_add(1);
+            }
+        } else {
+            self.same = 0;
+        }
+        self.previous = values;
+
+        let clipped = (0..3).any(|i| {
+            fabsf(a[i]) >= self.accel_limit || fabsf(g[i]) >= self.gyro_limit
+        });
+        if clipped {
+            self.clipped = self.clipped.saturating_add(1);
+            if self.clipped == SATURATED_SAMPLES {
+                self.counters.saturated =
+                    self.counters.saturated.wrapping_add(1);
+            }
+        } else {
+            self.clipped = 0;
+        }
+        self.failed |= self.same >= STUCK_SAMPLES;
+    }
+
+    #[inline]
+    pub fn bus_error(&mut self) {
+        self.counters.bus_errors = self.counters.bus_errors.wrapping_add(1);
+        self.failed = true;
+    }
+
+    #[inline]
+    pub fn bad_id(&mut self) {
+        self.counters.bad_id = self.counters.bad_id.wrapping_add(1);
+        self.failed = true;
+    }
+
+    #[inline]
+    pub fn reinit(&mut self) {
+        self.counters.reinits = self.counters.reinits.wrapping_add(1);
+    }
+
+    // counts steps; true when WHO_AM_I is to be checked in this one
+    #[inline]
+    pub fn check_due(&mut self, config: &Config) -> bool {
+        self.steps = self.steps.wrapping_add(1);
+        config.check_period > 0 && self.steps % config.check_period == 0
+    }
+
+    #[inline]
+    pub fn failing(&self) -> bool {
+        self.failed
+    }
+
+    // Ends the step; failsafe latches after `max_failures` in a row.
+    pub fn finish(&mut self, config: &Config) -> Counters {
+        let counters = &mut self.counters;
+        if self.failed {
+            counters.failures = counters.failures.saturating_add(1);
+            counters.failsafe |= counters.failures >= config.max_failures;
+        } else {
+            counters.failures = 0;
+        }
+        self.failed = false;
+        *counters
+    }
+
+    #[inline]
+    pub fn clear_failsafe(&mut self) {
+        self.counters.failsafe = false;
+        self.counters.failures = 0;
+    }
+}
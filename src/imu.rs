@@ -0,0 +1,443 @@
+// IMU drivers behind one trait, and their sampling settings: full-scale
+// ranges, DLPF and sample rate divisor.
+use core::f32::consts::PI;
+use ehal::blocking::delay::DelayMs;
+use libm::powf;
+use mpu9250::{AccelDataRate, AccelScale, Dlpf, GyroScale, GyroTempDataRate};
+
+pub mod icm42688;
+pub mod invensense;
+#[cfg(test)]
+mod mock;
+pub mod mpu;
+mod spi;
+
+pub const G: f32 = mpu9250::G;
+// part of full scale where readings count as clipped
+pub const CLIPPED: f32 = 0.99;
+
+// internal rate with DLPF enabled, Hz
+const INTERNAL_RATE_HZ: f32 = 1000.;
+// averaged with and without excitation, 1ms apart
+const SELF_TEST_SAMPLES: usize = 200;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Bus(E),
+    // unexpected WHO_AM_I
+    InvalidDevice(u8),
+    // bus was not given back after speed change
+    ReInit,
+}
+
+impl<E> From<E> for Error<E> {
+    #[inline]
+    fn from(e: E) -> Self {
+        Error::Bus(e)
+    }
+}
+
+// One reading of all sensors, magnetometer only in Marg mode
+pub struct Sample {
+    pub accel: [f32; 3],
+    pub gyro: [f32; 3],
+    pub mag: Option<[f32; 3]>,
+    // die temperature, degrees Celsius
+    pub temp_c: f32,
+}
+
+// Samples ready to be taken with `next_sample`
+pub struct Pending {
+    pub count: usize,
+    // some samples were lost since previous call
+    pub overflow: bool,
+    // None when not sampled at fixed rate
+    pub period_s: Option<f32>,
+}
+
+// accel x, y, z then gyro x, y, z, as read from chip
+pub type Raw = [i16; 6];
+
+// value of one LSB: m/s^2 and rad/s
+#[derive(Copy, Clone)]
+pub struct Scales {
+    pub accel: f32,
+    pub gyro: f32,
+}
+
+impl Scales {
+    #[inline]
+    pub fn new(config: &Config) -> Self {
+        Scales {
+            accel: config.accel_full_scale() / 32768.,
+            gyro: config.gyro_full_scale() / 32768.,
+        }
+    }
+
+    pub fn sample(&self, raw: &Raw, temp_c: f32) -> Sample {
+        let (a, g) = (self.accel, self.gyro);
+        Sample {
+            accel: [raw[0] as f32 * a, raw[1] as f32 * a, raw[2] as f32 * a],
+            gyro: [raw[3] as f32 * g, raw[4] as f32 * g, raw[5] as f32 * g],
+            mag: None,
+            temp_c,
+        }
+    }
+}
+
+pub trait Imu {
+    type Error;
+    fn who_am_i(&mut self) -> Result<u8, Self::Error>;
+    // current output registers
+    fn sample(&mut self) -> Result<Sample, Self::Error>;
+    fn configure(&mut self, config: &Config) -> Result<(), Self::Error>;
+    // data ready pulse on interrupt pin
+    fn enable_data_ready(&mut self) -> Result<(), Self::Error>;
+    // Wakes chip and applies current settings and data ready again,
+    // after it was reset or lost them; bus speed stays.
+    fn recover(&mut self) -> Result<(), Self::Error>;
+    fn scales(&self) -> Scales;
+    // actual rate, chips without divisor pick the closest one
+    fn sample_rate_hz(&self) -> f32;
+    // On-chip excitation against factory trimmed response; settings
+    // are restored afterwards. None when driver can't run it.
+    fn self_test<D>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<Option<bool>, Self::Error>
+    where
+        D: DelayMs<u8>;
+
+    // Buffered acquisition starts here, after calibration. Without
+    // buffering there is always one fresh sample per data ready.
+    #[inline]
+    fn start(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    #[inline]
+    fn pending(&mut self) -> Result<Pending, Self::Error> {
+        Ok(Pending {
+            count: 1,
+            overflow: false,
+            period_s: None,
+        })
+    }
+
+    #[inline]
+    fn next_sample(&mut self) -> Result<Sample, Self::Error> {
+        self.sample()
+    }
+}
+
+#[inline]
+fn i16_at(bytes: &[u8], at: usize) -> i16 {
+    i16::from_be_bytes([bytes[at], bytes[at + 1]])
+}
+
+// accel and gyro big endian triples at given byte offsets
+fn raw_from(bytes: &[u8], accel: usize, gyro: usize) -> Raw {
+    let value = |at: usize| i16_at(bytes, at);
+    [
+        value(accel),
+        value(accel + 2),
+        value(accel + 4),
+        value(gyro),
+        value(gyro + 2),
+        value(gyro + 4),
+    ]
+}
+
+fn average<D, F, E>(delay: &mut D, mut read: F) -> Result<[f32; 6], E>
+where
+    D: DelayMs<u8>,
+    F: FnMut() -> Result<Raw, E>,
+{
+    let mut sum = [0.0; 6];
+    for _ in 0..SELF_TEST_SAMPLES {
+        let raw = read()?;
+        for i in 0..6 {
+            sum[i] += raw[i] as f32;
+        }
+        delay.delay_ms(1);
+    }
+    for v in sum.iter_mut() {
+        *v /= SELF_TEST_SAMPLES as f32;
+    }
+    Ok(sum)
+}
+
+// Self-test response stored by factory as trim code, LSB; `shift` is
+// how many times test range is wider than 250dps or 2g.
+fn factory_response(code: u8, shift: u8) -> f32 {
+    2620. / (1 << shift) as f32 * powf(1.01, code as f32 - 1.)
+}
+
+// Codes are accel x, y, z then gyro x, y, z; zero means not trimmed.
+// Accel has to be within 50% of factory response, gyro at least half.
+fn self_test_passed(
+    normal: &[f32; 6],
+    excited: &[f32; 6],
+    codes: &[u8; 6],
+    accel_shift: u8,
+    gyro_shift: u8,
+) -> bool {
+    (0..6).all(|i| {
+        if codes[i] == 0 {
+            return false;
+        }
+        let shift = if i < 3 { accel_shift } else { gyro_shift };
+        let ratio =
+            (excited[i] - normal[i]) / factory_response(codes[i], shift);
+        if i < 3 {
+            ratio > 0.5 && ratio < 1.5
+        } else {
+            ratio > 0.5
+        }
+    })
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum GyroRange {
+    Dps250,
+    Dps500,
+    Dps1000,
+    Dps2000,
+}
+
+impl GyroRange {
+    #[inline]
+    pub fn from_dps(dps: i32) -> Option<Self> {
+        match dps {
+            250 => Some(GyroRange::Dps250),
+            500 => Some(GyroRange::Dps500),
+            1000 => Some(GyroRange::Dps1000),
+            2000 => Some(GyroRange::Dps2000),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn dps(&self) -> f32 {
+        match self {
+            GyroRange::Dps250 => 250.,
+            GyroRange::Dps500 => 500.,
+            GyroRange::Dps1000 => 1000.,
+            GyroRange::Dps2000 => 2000.,
+        }
+    }
+
+    // FS_SEL bits
+    #[inline]
+    pub fn bits(&self) -> u8 {
+        *self as u8
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum AccelRange {
+    G2,
+    G4,
+    G8,
+    G16,
+}
+
+impl AccelRange {
+    #[inline]
+    pub fn from_g(g: i32) -> Option<Self> {
+        match g {
+            2 => Some(AccelRange::G2),
+            4 => Some(AccelRange::G4),
+            8 => Some(AccelRange::G8),
+            16 => Some(AccelRange::G16),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn g(&self) -> f32 {
+        match self {
+            AccelRange::G2 => 2.,
+            AccelRange::G4 => 4.,
+            AccelRange::G8 => 8.,
+            AccelRange::G16 => 16.,
+        }
+    }
+
+    // ACCEL_FS_SEL bits
+    #[inline]
+    pub fn bits(&self) -> u8 {
+        *self as u8
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Config {
+    pub gyro_range: GyroRange,
+    pub accel_range: AccelRange,
+    // DLPF_CFG, same for gyro and accel: 1 - 184Hz .. 6 - 5Hz;
+    // 0 and 7 bypass divisor and are not supported
+    pub dlpf: u8,
+    // sample rate is 1kHz / (1 + divisor)
+    pub divisor: u8,
+}
+
+impl Config {
+    #[inline]
+    pub const fn new() -> Self {
+        Config {
+            gyro_range: GyroRange::Dps2000,
+            accel_range: AccelRange::G16,
+            dlpf: 2,
+            divisor: 3,
+        }
+    }
+
+    #[inline]
+    pub fn valid_dlpf(dlpf: i32) -> bool {
+        dlpf >= 1 && dlpf <= 6
+    }
+
+    // requested rate
+    #[inline]
+    pub fn sample_rate_hz(&self) -> f32 {
+        INTERNAL_RATE_HZ / (1. + self.divisor as f32)
+    }
+
+    // m/s^2
+    #[inline]
+    pub fn accel_full_scale(&self) -> f32 {
+        self.accel_range.g() * G
+    }
+
+    // rad/s
+    #[inline]
+    pub fn gyro_full_scale(&self) -> f32 {
+        self.gyro_range.dps() * PI / 180.
+    }
+
+    #[inline]
+    pub fn gyro_scale(&self) -> GyroScale {
+        match self.gyro_range {
+            GyroRange::Dps250 => GyroScale::_250DPS,
+            GyroRange::Dps500 => GyroScale::_500DPS,
+            GyroRange::Dps1000 => GyroScale::_1000DPS,
+            GyroRange::Dps2000 => GyroScale::_2000DPS,
+        }
+    }
+
+    #[inline]
+    pub fn accel_scale(&self) -> AccelScale {
+        match self.accel_range {
+            AccelRange::G2 => AccelScale::_2G,
+            AccelRange::G4 => AccelScale::_4G,
+            AccelRange::G8 => AccelScale::_8G,
+            AccelRange::G16 => AccelScale::_16G,
+        }
+    }
+
+    #[inline]
+    pub fn gyro_rate(&self) -> GyroTempDataRate {
+        GyroTempDataRate::DlpfConf(self.mpu_dlpf())
+    }
+
+    #[inline]
+    pub fn accel_rate(&self) -> AccelDataRate {
+        AccelDataRate::DlpfConf(self.mpu_dlpf())
+    }
+
+    fn mpu_dlpf(&self) -> Dlpf {
+        match self.dlpf {
+            1 => Dlpf::_1,
+            2 => Dlpf::_2,
+            3 => Dlpf::_3,
+            4 => Dlpf::_4,
+            5 => Dlpf::_5,
+            _ => Dlpf::_6,
+        }
+    }
+
+    // gyro_dps,accel_g,dlpf,divisor
+    #[inline]
+    pub fn results(&self) -> [f32; 4] {
+        [
+            self.gyro_range.dps(),
+            self.accel_range.g(),
+            self.dlpf as f32,
+            self.divisor as f32,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn factory_response_grows_one_percent_per_code() {
+        assert_eq!(factory_response(1, 0), 2620.);
+        assert_eq!(factory_response(1, 1), 1310.);
+        let step = factory_response(51, 0) / factory_response(50, 0);
+        assert!((step - 1.01).abs() < 1e-5);
+    }
+
+    // excited by `ratio` of factory response, accel then gyro
+    fn excited(codes: &[u8; 6], accel_shift: u8, ratio: [f32; 2]) -> [f32; 6] {
+        let mut out = [0.; 6];
+        for i in 0..6 {
+            let (shift, ratio) = if i < 3 {
+                (accel_shift, ratio[0])
+            } else {
+                (0, ratio[1])
+            };
+            out[i] = factory_response(codes[i], shift) * ratio;
+        }
+        out
+    }
+
+    #[test]
+    fn self_test_limits() {
+        let codes = [100; 6];
+        let normal = [0.; 6];
+        let passed = |ratio| {
+            let excited = excited(&codes, 0, ratio);
+            self_test_passed(&normal, &excited, &codes, 0, 0)
+        };
+        assert!(passed([1., 1.]));
+        assert!(passed([1.4, 0.6]));
+        // gyro has no upper limit
+        assert!(passed([1., 3.]));
+        assert!(!passed([0.4, 1.]));
+        assert!(!passed([1.6, 1.]));
+        assert!(!passed([1., 0.4]));
+    }
+
+    #[test]
+    fn self_test_response_is_relative_to_normal() {
+        let codes = [100; 6];
+        let normal = [4000.; 6];
+        let mut excited = excited(&codes, 0, [1., 1.]);
+        assert!(!self_test_passed(&normal, &excited, &codes, 0, 0));
+        for v in excited.iter_mut() {
+            *v += 4000.;
+        }
+        assert!(self_test_passed(&normal, &excited, &codes, 0, 0));
+    }
+
+    #[test]
+    fn self_test_accel_range_shift() {
+        let codes = [100; 6];
+        let excited = excited(&codes, 1, [1., 1.]);
+        assert!(self_test_passed(&[0.; 6], &excited, &codes, 1, 0));
+        // half the response expected at 2g
+        assert!(!self_test_passed(&[0.; 6], &excited, &codes, 0, 0));
+    }
+
+    #[test]
+    fn self_test_fails_untrimmed() {
+        let mut codes = [100; 6];
+        let excited = excited(&codes, 0, [1., 1.]);
+        codes[4] = 0;
+        assert!(!self_test_passed(&[0.; 6], &excited, &codes, 0, 0));
+    }
+}
@@ -0,0 +1,372 @@
+// ICM-42688-P accel and gyro over SPI, read through on-chip FIFO. Rate
+// is chosen from fixed output data rates instead of a divisor, and
+// `Config::dlpf` selects UI filter bandwidth: 1 - ODR/2 .. 6 - ODR/16.
+use ehal::blocking::delay::DelayMs;
+use ehal::blocking::spi;
+use ehal::digital::v2::OutputPin;
+
+use super::spi::Registers;
+use super::{Config, Error, Imu, Pending, Raw, Sample, Scales};
+
+// bank 0
+const DEVICE_CONFIG: u8 = 0x11;
+const INT_CONFIG: u8 = 0x14;
+const FIFO_CONFIG: u8 = 0x16;
+const TEMP_DATA1: u8 = 0x1d;
+const INT_STATUS: u8 = 0x2d;
+const FIFO_COUNTH: u8 = 0x2e;
+const FIFO_DATA: u8 = 0x30;
+const SIGNAL_PATH_RESET: u8 = 0x4b;
+const PWR_MGMT0: u8 = 0x4e;
+const GYRO_CONFIG0: u8 = 0x4f;
+const ACCEL_CONFIG0: u8 = 0x50;
+const GYRO_ACCEL_CONFIG0: u8 = 0x52;
+const FIFO_CONFIG1: u8 = 0x5f;
+const INT_CONFIG1: u8 = 0x64;
+const INT_SOURCE0: u8 = 0x65;
+const SELF_TEST_CONFIG: u8 = 0x70;
+const WHO_AM_I: u8 = 0x75;
+// all banks
+const REG_BANK_SEL: u8 = 0x76;
+// bank 1 and 2
+const XG_ST_DATA: u8 = 0x5f;
+const XA_ST_DATA: u8 = 0x3b;
+
+const ID: u8 = 0x47;
+// DEVICE_CONFIG
+const SOFT_RESET: u8 = 1;
+// INT_CONFIG: INT1 pulsed, push-pull, active high
+const INT1_PUSH_PULL_HIGH: u8 = 0b11;
+// INT_CONFIG1: has to be cleared for proper operation
+const INT_ASYNC_RESET: u8 = 1 << 4;
+// INT_SOURCE0
+const UI_DRDY_INT1_EN: u8 = 1 << 3;
+// INT_STATUS
+const FIFO_FULL_INT: u8 = 1 << 1;
+// FIFO_CONFIG: stop writing when full
+const FIFO_STOP_ON_FULL: u8 = 0b10 << 6;
+// FIFO_CONFIG1: accel and gyro, 16 byte packets
+const FIFO_ACCEL_GYRO: u8 = 0b11;
+// SIGNAL_PATH_RESET
+const FIFO_FLUSH: u8 = 1 << 1;
+// PWR_MGMT0: gyro and accel in low noise mode
+const LOW_NOISE: u8 = 0b1111;
+// SELF_TEST_CONFIG: accel power, accel x, y, z, gyro x, y, z
+const SELF_TEST: u8 = 0b111_1111;
+// GYRO_CONFIG0, ACCEL_CONFIG0: full-scale select above ODR,
+// most sensitive range has the highest code
+const FS_SEL_SHIFT: u8 = 5;
+const FS_SEL_MAX: u8 = 3;
+// self-test is specified at 250dps and 4g, 1kHz
+const SELF_TEST_ACCEL_FS: u8 = 2;
+const SELF_TEST_ODR: u8 = 6;
+
+// header, accel, gyro, temperature, timestamp
+const PACKET_LEN: usize = 16;
+// (Hz, ODR code), descending
+const RATES: [(f32, u8); 6] = [
+    (1000., 6),
+    (500., 15),
+    (200., 7),
+    (100., 8),
+    (50., 9),
+    (25., 10),
+];
+
+const RESET_MS: u8 = 10;
+// gyro start up
+const POWER_ON_MS: u8 = 50;
+const SETTLE_MS: u8 = 100;
+
+pub struct Icm42688<SPI, NCS> {
+    regs: Registers<SPI, NCS>,
+    config: Config,
+    scales: Scales,
+    sample_rate_hz: f32,
+}
+
+impl<SPI, NCS, E> Icm42688<SPI, NCS>
+where
+    SPI: spi::Transfer<u8, Error = E>,
+    NCS: OutputPin,
+{
+    // Resets and identifies chip on slow bus, then `reinit` gives it
+    // back at full speed for configuration and sampling.
+    pub fn with_reinit<D, F>(
+        spi: SPI,
+        ncs: NCS,
+        delay: &mut D,
+        config: &Config,
+        reinit: F,
+    ) -> Result<Self, Error<E>>
+    where
+        D: DelayMs<u8>,
+        F: FnOnce(SPI, NCS) -> Option<(SPI, NCS)>,
+    {
+        let mut regs = Registers::new(spi, ncs);
+        regs.write(REG_BANK_SEL, 0)?;
+        regs.write(DEVICE_CONFIG, SOFT_RESET)?;
+        delay.delay_ms(RESET_MS);
+        let id = regs.read(WHO_AM_I)?;
+        if id != ID {
+            return Err(Error::InvalidDevice(id));
+        }
+        wake(&mut regs)?;
+        delay.delay_ms(POWER_ON_MS);
+
+        let (spi, ncs) = regs.release();
+        let (spi, ncs) = reinit(spi, ncs).ok_or(Error::ReInit)?;
+        let mut imu = Icm42688 {
+            regs: Registers::new(spi, ncs),
+            config: *config,
+            scales: Scales::new(config),
+            sample_rate_hz: 0.,
+        };
+        imu.configure(config)?;
+        Ok(imu)
+    }
+
+    // Empties FIFO and clears full flag
+    fn restart(&mut self) -> Result<(), E> {
+        let regs = &mut self.regs;
+        regs.write(FIFO_CONFIG, FIFO_STOP_ON_FULL)?;
+        regs.write(FIFO_CONFIG1, FIFO_ACCEL_GYRO)?;
+        regs.write(SIGNAL_PATH_RESET, FIFO_FLUSH)?;
+        regs.read(INT_STATUS)?;
+        Ok(())
+    }
+
+    // temperature, accel, gyro
+    fn raw(&mut self) -> Result<(Raw, f32), E> {
+        let mut buffer = [0; 15];
+        buffer[0] = TEMP_DATA1;
+        self.regs.read_many(&mut buffer)?;
+        let temp_c = super::i16_at(&buffer, 1) as f32 / 132.48 + 25.;
+        Ok((super::raw_from(&buffer, 3, 9), temp_c))
+    }
+
+    fn read_bank(&mut self, bank: u8, reg: u8) -> Result<[u8; 3], E> {
+        let mut buffer = [0; 4];
+        buffer[0] = reg;
+        self.regs.write(REG_BANK_SEL, bank)?;
+        let res = self.regs.read_many(&mut buffer);
+        self.regs.write(REG_BANK_SEL, 0)?;
+        res.map(|_| [buffer[1], buffer[2], buffer[3]])
+    }
+}
+
+// interrupt pin and sensors on; gyro needs POWER_ON_MS to settle
+fn wake<SPI, NCS, E>(regs: &mut Registers<SPI, NCS>) -> Result<(), E>
+where
+    SPI: spi::Transfer<u8, Error = E>,
+    NCS: OutputPin,
+{
+    regs.write(REG_BANK_SEL, 0)?;
+    regs.write(INT_CONFIG, INT1_PUSH_PULL_HIGH)?;
+    regs.modify(INT_CONFIG1, |v| v & !INT_ASYNC_RESET)?;
+    regs.write(PWR_MGMT0, LOW_NOISE)
+}
+
+// fastest supported rate not above requested one
+fn output_data_rate(requested_hz: f32) -> (f32, u8) {
+    RATES
+        .iter()
+        .find(|(hz, _)| *hz <= requested_hz + 0.5)
+        .copied()
+        .unwrap_or(RATES[RATES.len() - 1])
+}
+
+impl<SPI, NCS, E> Imu for Icm42688<SPI, NCS>
+where
+    SPI: spi::Transfer<u8, Error = E>,
+    NCS: OutputPin,
+{
+    type Error = E;
+
+    #[inline]
+    fn who_am_i(&mut self) -> Result<u8, E> {
+        self.regs.read(WHO_AM_I)
+    }
+
+    fn sample(&mut self) -> Result<Sample, E> {
+        let (raw, temp_c) = self.raw()?;
+        Ok(self.scales.sample(&raw, temp_c))
+    }
+
+    // Buffered samples have old scale, so FIFO starts over
+    fn configure(&mut self, config: &Config) -> Result<(), E> {
+        let (hz, odr) = output_data_rate(config.sample_rate_hz());
+        let gyro_fs = FS_SEL_MAX - config.gyro_range.bits();
+        let accel_fs = FS_SEL_MAX - config.accel_range.bits();
+        let bandwidth = config.dlpf.saturating_sub(1);
+        let regs = &mut self.regs;
+        regs.write(GYRO_CONFIG0, (gyro_fs << FS_SEL_SHIFT) | odr)?;
+        regs.write(ACCEL_CONFIG0, (accel_fs << FS_SEL_SHIFT) | odr)?;
+        regs.write(GYRO_ACCEL_CONFIG0, (bandwidth << 4) | bandwidth)?;
+        self.config = *config;
+        self.scales = Scales::new(config);
+        self.sample_rate_hz = hz;
+        self.restart()
+    }
+
+    #[inline]
+    fn enable_data_ready(&mut self) -> Result<(), E> {
+        self.regs.write(INT_SOURCE0, UI_DRDY_INT1_EN)
+    }
+
+    fn recover(&mut self) -> Result<(), E> {
+        wake(&mut self.regs)?;
+        let config = self.config;
+        self.configure(&config)?;
+        self.enable_data_ready()
+    }
+
+    #[inline]
+    fn scales(&self) -> Scales {
+        self.scales
+    }
+
+    #[inline]
+    fn sample_rate_hz(&self) -> f32 {
+        self.sample_rate_hz
+    }
+
+    fn self_test<D>(&mut self, delay: &mut D) -> Result<Option<bool>, E>
+    where
+        D: DelayMs<u8>,
+    {
+        let saved = self.config;
+        let regs = &mut self.regs;
+        regs.write(GYRO_CONFIG0, (FS_SEL_MAX << FS_SEL_SHIFT) | SELF_TEST_ODR)?;
+        regs.write(
+            ACCEL_CONFIG0,
+            (SELF_TEST_ACCEL_FS << FS_SEL_SHIFT) | SELF_TEST_ODR,
+        )?;
+        delay.delay_ms(SETTLE_MS);
+        let normal = super::average(delay, || self.raw().map(|r| r.0))?;
+
+        self.regs.write(SELF_TEST_CONFIG, SELF_TEST)?;
+        delay.delay_ms(SETTLE_MS);
+        let excited = super::average(delay, || self.raw().map(|r| r.0))?;
+        self.regs.write(SELF_TEST_CONFIG, 0)?;
+        delay.delay_ms(SETTLE_MS);
+
+        let accel = self.read_bank(2, XA_ST_DATA)?;
+        let gyro = self.read_bank(1, XG_ST_DATA)?;
+        let codes = [accel[0], accel[1], accel[2], gyro[0], gyro[1], gyro[2]];
+        self.configure(&saved)?;
+        // 4g is twice 2g range
+        let passed =
+            super::self_test_passed(&normal, &excited, &codes, 1, 0);
+        Ok(Some(passed))
+    }
+
+    #[inline]
+    fn start(&mut self) -> Result<(), E> {
+        self.restart()
+    }
+
+    // Full FIFO has stopped taking samples, so it is restarted and
+    // acquisition continues with the next one.
+    fn pending(&mut self) -> Result<Pending, E> {
+        let overflow = self.regs.read(INT_STATUS)? & FIFO_FULL_INT != 0;
+        let count = if overflow {
+            self.restart()?;
+            0
+        } else {
+            let mut buffer = [FIFO_COUNTH, 0, 0];
+            self.regs.read_many(&mut buffer)?;
+            let bytes = u16::from_be_bytes([buffer[1], buffer[2]]);
+            bytes as usize / PACKET_LEN
+        };
+        Ok(Pending {
+            count,
+            overflow,
+            period_s: Some(1. / self.sample_rate_hz),
+        })
+    }
+
+    // count comes from FIFO_COUNT, so header is not checked for empty
+    fn next_sample(&mut self) -> Result<Sample, E> {
+        let mut buffer = [0; PACKET_LEN + 1];
+        buffer[0] = FIFO_DATA;
+        self.regs.read_many(&mut buffer)?;
+        // buffer[1] is header, temperature is one byte here
+        let raw = super::raw_from(&buffer, 2, 8);
+        let temp_c = buffer[14] as i8 as f32 / 2.07 + 25.;
+        Ok(self.scales.sample(&raw, temp_c))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock::{Bus, Ncs};
+    use super::super::G;
+    use super::*;
+
+    fn device() -> (Bus, Icm42688<Bus, Ncs>) {
+        let bus = Bus::new();
+        bus.chip().fifo_reg = Some(FIFO_DATA);
+        let config = Config::new();
+        let imu = Icm42688 {
+            regs: Registers::new(bus.clone(), bus.ncs()),
+            config,
+            scales: Scales::new(&config),
+            sample_rate_hz: 200.,
+        };
+        (bus, imu)
+    }
+
+    fn packet(accel: [i16; 3], gyro: [i16; 3], temp: i8) -> Vec<u8> {
+        let mut out = vec![0x68];
+        for v in accel.iter().chain(gyro.iter()) {
+            out.extend_from_slice(&v.to_be_bytes());
+        }
+        out.push(temp as u8);
+        // timestamp
+        out.extend_from_slice(&[0x12, 0x34]);
+        out
+    }
+
+    #[test]
+    fn fifo_packet_is_header_accel_gyro_temp_timestamp() {
+        let (bus, mut imu) = device();
+        let packet = packet([1, -2, 2048], [16, -32, 64], 21);
+        assert_eq!(packet.len(), PACKET_LEN);
+        bus.chip().fifo.extend(packet);
+        let sample = imu.next_sample().unwrap();
+        let Scales { accel: a, gyro: g } = imu.scales();
+        assert_eq!(sample.accel, [a, -2. * a, 2048. * a]);
+        assert_eq!(sample.gyro, [16. * g, -32. * g, 64. * g]);
+        assert!((sample.accel[2] - G).abs() < 1e-4);
+        assert!((sample.temp_c - 35.14).abs() < 1e-2);
+        assert!(bus.chip().fifo.is_empty());
+        let frames = &bus.chip().frames;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].len(), PACKET_LEN + 1);
+        assert_eq!(frames[0][0], 0x80 | FIFO_DATA);
+    }
+
+    #[test]
+    fn temperature_is_signed() {
+        let (bus, mut imu) = device();
+        bus.chip().fifo.extend(packet([0; 3], [0; 3], -21));
+        assert!((imu.next_sample().unwrap().temp_c - 14.86).abs() < 1e-2);
+    }
+
+    #[test]
+    fn output_data_rate_is_closest_not_above() {
+        assert_eq!(output_data_rate(1000.), (1000., 6));
+        assert_eq!(output_data_rate(500.), (500., 15));
+        assert_eq!(output_data_rate(250.), (200., 7));
+        // half a hertz of slack
+        assert_eq!(output_data_rate(199.6), (200., 7));
+        assert_eq!(output_data_rate(25.), (25., 10));
+    }
+
+    #[test]
+    fn output_data_rate_is_bounded() {
+        assert_eq!(output_data_rate(8000.), (1000., 6));
+        assert_eq!(output_data_rate(1.), (25., 10));
+    }
+}
@@ -0,0 +1,385 @@
+// MPU9250 and ICM-20602 accel and gyro over SPI, read through on-chip
+// FIFO. Both share register map; what differs is in `Variant`.
+use ehal::blocking::delay::DelayMs;
+use ehal::blocking::spi;
+use ehal::digital::v2::OutputPin;
+
+use super::spi::Registers;
+use super::{Config, Error, Imu, Pending, Raw, Sample, Scales};
+
+const SELF_TEST_X_ACCEL: u8 = 0x0d;
+const SMPLRT_DIV: u8 = 0x19;
+const CONFIG: u8 = 0x1a;
+const GYRO_CONFIG: u8 = 0x1b;
+const ACCEL_CONFIG: u8 = 0x1c;
+const ACCEL_CONFIG_2: u8 = 0x1d;
+const FIFO_EN: u8 = 0x23;
+const INT_ENABLE: u8 = 0x38;
+const INT_STATUS: u8 = 0x3a;
+const ACCEL_XOUT_H: u8 = 0x3b;
+const USER_CTRL: u8 = 0x6a;
+const PWR_MGMT_1: u8 = 0x6b;
+const PWR_MGMT_2: u8 = 0x6c;
+// ICM-20602 only
+const I2C_IF: u8 = 0x70;
+const FIFO_COUNT_H: u8 = 0x72;
+const FIFO_R_W: u8 = 0x74;
+const WHO_AM_I: u8 = 0x75;
+
+// CONFIG: stop writing when full instead of overwriting oldest bytes,
+// so packets never get torn
+const FIFO_MODE: u8 = 1 << 6;
+// GYRO_CONFIG, ACCEL_CONFIG: full-scale select, self-test x, y, z
+const FS_SEL_SHIFT: u8 = 3;
+const FS_SEL: u8 = 0b11 << FS_SEL_SHIFT;
+const SELF_TEST: u8 = 0b111 << 5;
+// GYRO_CONFIG: FCHOICE_B, zero keeps DLPF on
+const FCHOICE_B: u8 = 0b11;
+// CONFIG, ACCEL_CONFIG_2: DLPF_CFG, ACCEL_FCHOICE_B with it
+const DLPF_CFG: u8 = 0b111;
+const ACCEL_DLPF: u8 = 0b1111;
+// self-test is specified at 92Hz bandwidth
+const SELF_TEST_DLPF: u8 = 2;
+// INT_ENABLE
+const RAW_RDY_EN: u8 = 1;
+// INT_STATUS
+const FIFO_OFLOW_INT: u8 = 1 << 4;
+// USER_CTRL
+const USER_FIFO_EN: u8 = 1 << 6;
+const USER_I2C_IF_DIS: u8 = 1 << 4;
+const USER_FIFO_RST: u8 = 1 << 2;
+// I2C_IF
+const I2C_IF_DIS: u8 = 1 << 6;
+// PWR_MGMT_1
+const H_RESET: u8 = 1 << 7;
+const CLKSEL_AUTO: u8 = 1;
+
+const RESET_MS: u8 = 100;
+const SETTLE_MS: u8 = 20;
+
+// accel, temperature, gyro
+const PACKET_LEN: usize = 14;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Variant {
+    Mpu9250,
+    Icm20602,
+}
+
+impl Variant {
+    // MPU9255 answers differently, but is the same otherwise
+    fn ids(&self) -> &'static [u8] {
+        match self {
+            Variant::Mpu9250 => &[0x71, 0x73],
+            Variant::Icm20602 => &[0x12],
+        }
+    }
+
+    // temperature, gyro and accel; ICM-20602 writes temperature along
+    // with gyro
+    fn fifo_en(&self) -> u8 {
+        match self {
+            Variant::Mpu9250 => 0b1111_1000,
+            Variant::Icm20602 => 0b0001_1000,
+        }
+    }
+
+    fn temp_c(&self, raw: i16) -> f32 {
+        match self {
+            Variant::Mpu9250 => raw as f32 / 333.87 + 21.,
+            Variant::Icm20602 => raw as f32 / 326.8 + 25.,
+        }
+    }
+
+    // factory self-test codes of gyro x, y, z
+    fn self_test_gyro(&self) -> u8 {
+        match self {
+            Variant::Mpu9250 => 0x00,
+            Variant::Icm20602 => 0x50,
+        }
+    }
+}
+
+pub struct Invensense<SPI, NCS> {
+    regs: Registers<SPI, NCS>,
+    variant: Variant,
+    config: Config,
+    scales: Scales,
+}
+
+impl<SPI, NCS, E> Invensense<SPI, NCS>
+where
+    SPI: spi::Transfer<u8, Error = E>,
+    NCS: OutputPin,
+{
+    // Resets and identifies chip on slow bus, then `reinit` gives it
+    // back at full speed for configuration and sampling.
+    pub fn with_reinit<D, F>(
+        spi: SPI,
+        ncs: NCS,
+        delay: &mut D,
+        variant: Variant,
+        config: &Config,
+        reinit: F,
+    ) -> Result<Self, Error<E>>
+    where
+        D: DelayMs<u8>,
+        F: FnOnce(SPI, NCS) -> Option<(SPI, NCS)>,
+    {
+        let mut regs = Registers::new(spi, ncs);
+        regs.write(PWR_MGMT_1, H_RESET)?;
+        delay.delay_ms(RESET_MS);
+        wake(&mut regs, variant)?;
+        let id = regs.read(WHO_AM_I)?;
+        if !variant.ids().contains(&id) {
+            return Err(Error::InvalidDevice(id));
+        }
+
+        let (spi, ncs) = regs.release();
+        let (spi, ncs) = reinit(spi, ncs).ok_or(Error::ReInit)?;
+        let mut imu = Invensense {
+            regs: Registers::new(spi, ncs),
+            variant,
+            config: *config,
+            scales: Scales::new(config),
+        };
+        imu.configure(config)?;
+        Ok(imu)
+    }
+
+    // Empties FIFO and clears overflow flag
+    fn restart(&mut self) -> Result<(), E> {
+        let regs = &mut self.regs;
+        regs.write(FIFO_EN, 0)?;
+        regs.modify(USER_CTRL, |v| (v & !USER_FIFO_EN) | USER_FIFO_RST)?;
+        regs.modify(CONFIG, |v| v | FIFO_MODE)?;
+        regs.read(INT_STATUS)?;
+        regs.modify(USER_CTRL, |v| v | USER_FIFO_EN)?;
+        regs.write(FIFO_EN, self.variant.fifo_en())
+    }
+
+    // output registers, laid out same as FIFO packet
+    fn raw(&mut self) -> Result<(Raw, f32), E> {
+        self.read_packet(ACCEL_XOUT_H)
+    }
+
+    fn read_packet(&mut self, reg: u8) -> Result<(Raw, f32), E> {
+        let mut buffer = [0; PACKET_LEN + 1];
+        buffer[0] = reg;
+        self.regs.read_many(&mut buffer)?;
+        let temp_c = self.variant.temp_c(super::i16_at(&buffer, 7));
+        Ok((super::raw_from(&buffer, 1, 9), temp_c))
+    }
+
+    fn self_test_codes(&mut self) -> Result<[u8; 6], E> {
+        let mut accel = [0; 4];
+        accel[0] = SELF_TEST_X_ACCEL;
+        self.regs.read_many(&mut accel)?;
+        let mut gyro = [0; 4];
+        gyro[0] = self.variant.self_test_gyro();
+        self.regs.read_many(&mut gyro)?;
+        Ok([accel[1], accel[2], accel[3], gyro[1], gyro[2], gyro[3]])
+    }
+}
+
+// clock from gyro, all axes on, SPI only
+fn wake<SPI, NCS, E>(
+    regs: &mut Registers<SPI, NCS>,
+    variant: Variant,
+) -> Result<(), E>
+where
+    SPI: spi::Transfer<u8, Error = E>,
+    NCS: OutputPin,
+{
+    regs.write(PWR_MGMT_1, CLKSEL_AUTO)?;
+    regs.write(PWR_MGMT_2, 0)?;
+    match variant {
+        Variant::Mpu9250 => regs.modify(USER_CTRL, |v| v | USER_I2C_IF_DIS),
+        Variant::Icm20602 => regs.write(I2C_IF, I2C_IF_DIS),
+    }
+}
+
+impl<SPI, NCS, E> Imu for Invensense<SPI, NCS>
+where
+    SPI: spi::Transfer<u8, Error = E>,
+    NCS: OutputPin,
+{
+    type Error = E;
+
+    #[inline]
+    fn who_am_i(&mut self) -> Result<u8, E> {
+        self.regs.read(WHO_AM_I)
+    }
+
+    fn sample(&mut self) -> Result<Sample, E> {
+        let (raw, temp_c) = self.raw()?;
+        Ok(self.scales.sample(&raw, temp_c))
+    }
+
+    // Buffered samples have old scale, so FIFO starts over
+    fn configure(&mut self, config: &Config) -> Result<(), E> {
+        let gyro_fs = config.gyro_range.bits() << FS_SEL_SHIFT;
+        let accel_fs = config.accel_range.bits() << FS_SEL_SHIFT;
+        let dlpf = config.dlpf;
+        let regs = &mut self.regs;
+        regs.modify(GYRO_CONFIG, |v| (v & !(FS_SEL | FCHOICE_B)) | gyro_fs)?;
+        regs.modify(ACCEL_CONFIG, |v| (v & !FS_SEL) | accel_fs)?;
+        regs.modify(CONFIG, |v| (v & !DLPF_CFG) | dlpf)?;
+        regs.modify(ACCEL_CONFIG_2, |v| (v & !ACCEL_DLPF) | dlpf)?;
+        regs.write(SMPLRT_DIV, config.divisor)?;
+        self.config = *config;
+        self.scales = Scales::new(config);
+        self.restart()
+    }
+
+    #[inline]
+    fn enable_data_ready(&mut self) -> Result<(), E> {
+        self.regs.write(INT_ENABLE, RAW_RDY_EN)
+    }
+
+    fn recover(&mut self) -> Result<(), E> {
+        wake(&mut self.regs, self.variant)?;
+        let config = self.config;
+        self.configure(&config)?;
+        self.enable_data_ready()
+    }
+
+    #[inline]
+    fn scales(&self) -> Scales {
+        self.scales
+    }
+
+    #[inline]
+    fn sample_rate_hz(&self) -> f32 {
+        self.config.sample_rate_hz()
+    }
+
+    // Per application note: 1kHz, 92Hz DLPF, 250dps and 2g
+    fn self_test<D>(&mut self, delay: &mut D) -> Result<Option<bool>, E>
+    where
+        D: DelayMs<u8>,
+    {
+        let saved = self.config;
+        let regs = &mut self.regs;
+        regs.write(SMPLRT_DIV, 0)?;
+        regs.modify(CONFIG, |v| (v & !DLPF_CFG) | SELF_TEST_DLPF)?;
+        regs.modify(ACCEL_CONFIG_2, |v| (v & !ACCEL_DLPF) | SELF_TEST_DLPF)?;
+        regs.write(GYRO_CONFIG, 0)?;
+        regs.write(ACCEL_CONFIG, 0)?;
+        delay.delay_ms(SETTLE_MS);
+        let normal = super::average(delay, || self.raw().map(|r| r.0))?;
+
+        self.regs.write(GYRO_CONFIG, SELF_TEST)?;
+        self.regs.write(ACCEL_CONFIG, SELF_TEST)?;
+        delay.delay_ms(SETTLE_MS);
+        let excited = super::average(delay, || self.raw().map(|r| r.0))?;
+
+        self.regs.write(GYRO_CONFIG, 0)?;
+        self.regs.write(ACCEL_CONFIG, 0)?;
+        delay.delay_ms(SETTLE_MS);
+        let codes = self.self_test_codes()?;
+        self.configure(&saved)?;
+        let passed =
+            super::self_test_passed(&normal, &excited, &codes, 0, 0);
+        Ok(Some(passed))
+    }
+
+    #[inline]
+    fn start(&mut self) -> Result<(), E> {
+        self.restart()
+    }
+
+    // Overflowed FIFO has lost samples already, so it is restarted and
+    // acquisition continues with the next one.
+    fn pending(&mut self) -> Result<Pending, E> {
+        let overflow = self.regs.read(INT_STATUS)? & FIFO_OFLOW_INT != 0;
+        let count = if overflow {
+            self.restart()?;
+            0
+        } else {
+            let mut buffer = [FIFO_COUNT_H, 0, 0];
+            self.regs.read_many(&mut buffer)?;
+            let bytes = u16::from_be_bytes([buffer[1] & 0x1f, buffer[2]]);
+            bytes as usize / PACKET_LEN
+        };
+        Ok(Pending {
+            count,
+            overflow,
+            period_s: Some(1. / self.sample_rate_hz()),
+        })
+    }
+
+    fn next_sample(&mut self) -> Result<Sample, E> {
+        let (raw, temp_c) = self.read_packet(FIFO_R_W)?;
+        Ok(self.scales.sample(&raw, temp_c))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock::{Bus, Ncs};
+    use super::super::G;
+    use super::*;
+
+    fn device(variant: Variant) -> (Bus, Invensense<Bus, Ncs>) {
+        let bus = Bus::new();
+        bus.chip().fifo_reg = Some(FIFO_R_W);
+        let config = Config::new();
+        let imu = Invensense {
+            regs: Registers::new(bus.clone(), bus.ncs()),
+            variant,
+            config,
+            scales: Scales::new(&config),
+        };
+        (bus, imu)
+    }
+
+    // accel x, y, z, temperature, gyro x, y, z
+    fn packet(values: [i16; 7]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|v| v.to_be_bytes().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn fifo_packet_is_accel_temp_gyro() {
+        let (bus, mut imu) = device(Variant::Mpu9250);
+        let packet = packet([1, -2, 2048, 0, 16, -32, 64]);
+        assert_eq!(packet.len(), PACKET_LEN);
+        bus.chip().fifo.extend(packet);
+        let sample = imu.next_sample().unwrap();
+        let Scales { accel: a, gyro: g } = imu.scales();
+        assert_eq!(sample.accel, [a, -2. * a, 2048. * a]);
+        assert_eq!(sample.gyro, [16. * g, -32. * g, 64. * g]);
+        // 16g range
+        assert!((sample.accel[2] - G).abs() < 1e-4);
+        assert_eq!(sample.temp_c, 21.);
+        assert!(sample.mag.is_none());
+        assert!(bus.chip().fifo.is_empty());
+        let frames = &bus.chip().frames;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].len(), PACKET_LEN + 1);
+        assert_eq!(frames[0][0], 0x80 | FIFO_R_W);
+    }
+
+    #[test]
+    fn fifo_packets_come_in_order() {
+        let (bus, mut imu) = device(Variant::Mpu9250);
+        bus.chip().fifo.extend(packet([0, 0, 1, 0, 0, 0, 0]));
+        bus.chip().fifo.extend(packet([0, 0, 2, 0, 0, 0, 0]));
+        let a = imu.scales().accel;
+        assert_eq!(imu.next_sample().unwrap().accel[2], a);
+        assert_eq!(imu.next_sample().unwrap().accel[2], 2. * a);
+    }
+
+    #[test]
+    fn temperature_depends_on_variant() {
+        let (bus, mut imu) = device(Variant::Icm20602);
+        bus.chip().fifo.extend(packet([0, 0, 0, 3268, 0, 0, 0]));
+        assert!((imu.next_sample().unwrap().temp_c - 35.).abs() < 1e-3);
+        let (bus, mut imu) = device(Variant::Mpu9250);
+        bus.chip().fifo.extend(packet([0, 0, 0, 3339, 0, 0, 0]));
+        assert!((imu.next_sample().unwrap().temp_c - 31.).abs() < 1e-2);
+    }
+}
@@ -0,0 +1,82 @@
+// Chip on SPI for driver tests: flat register file and a FIFO streamed
+// from one register. Bytes sent in each chip select frame are recorded.
+use std::cell::{RefCell, RefMut};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use ehal::blocking::spi;
+use ehal::digital::v2::OutputPin;
+
+pub struct Chip {
+    pub regs: [u8; 128],
+    pub fifo_reg: Option<u8>,
+    pub fifo: VecDeque<u8>,
+    pub frames: Vec<Vec<u8>>,
+    pub selected: bool,
+}
+
+#[derive(Clone)]
+pub struct Bus(Rc<RefCell<Chip>>);
+
+impl Bus {
+    pub fn new() -> Self {
+        Bus(Rc::new(RefCell::new(Chip {
+            regs: [0; 128],
+            fifo_reg: None,
+            fifo: VecDeque::new(),
+            frames: Vec::new(),
+            selected: false,
+        })))
+    }
+
+    pub fn chip(&self) -> RefMut<'_, Chip> {
+        self.0.borrow_mut()
+    }
+
+    pub fn ncs(&self) -> Ncs {
+        Ncs(self.clone())
+    }
+}
+
+impl spi::Transfer<u8> for Bus {
+    type Error = ();
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], ()> {
+        let mut chip = self.chip();
+        assert!(chip.selected, "transfer without chip select");
+        chip.frames.last_mut().unwrap().extend_from_slice(words);
+        let reg = words[0] & 0x7f;
+        if words[0] & 0x80 == 0 {
+            chip.regs[reg as usize] = words[1];
+        } else if chip.fifo_reg == Some(reg) {
+            for w in words[1..].iter_mut() {
+                *w = chip.fifo.pop_front().unwrap_or(0);
+            }
+        } else {
+            for (i, w) in words[1..].iter_mut().enumerate() {
+                *w = chip.regs[reg as usize + i];
+            }
+        }
+        Ok(words)
+    }
+}
+
+pub struct Ncs(Bus);
+
+impl OutputPin for Ncs {
+    type Error = ();
+
+    fn set_low(&mut self) -> Result<(), ()> {
+        let mut chip = self.0.chip();
+        assert!(!chip.selected, "chip selected twice");
+        chip.selected = true;
+        chip.frames.push(Vec::new());
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), ()> {
+        self.0.chip().selected = false;
+        Ok(())
+    }
+}
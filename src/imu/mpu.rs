@@ -0,0 +1,130 @@
+// MPU9250 through `mpu9250` driver crate: single samples from output
+// registers, and the only way to magnetometer (AK8963 behind MPU9250's
+// I2C master).
+use ehal::blocking::delay::DelayMs;
+use mpu9250::Mpu9250;
+
+use super::{Config, Imu, Sample, Scales};
+
+// Reading of crate's `Imu` or `Marg` mode
+pub trait Measure {
+    type Error;
+    fn measure(&mut self) -> Result<Sample, Self::Error>;
+}
+
+impl<DEV, E> Measure for Mpu9250<DEV, mpu9250::Imu>
+where
+    DEV: mpu9250::Device<Error = E>,
+{
+    type Error = E;
+
+    #[inline]
+    fn measure(&mut self) -> Result<Sample, E> {
+        let meas = self.all::<[f32; 3]>()?;
+        Ok(Sample {
+            accel: meas.accel,
+            gyro: meas.gyro,
+            mag: None,
+            temp_c: meas.temp,
+        })
+    }
+}
+
+impl<DEV, E> Measure for Mpu9250<DEV, mpu9250::Marg>
+where
+    DEV: mpu9250::Device<Error = E>,
+{
+    type Error = E;
+
+    #[inline]
+    fn measure(&mut self) -> Result<Sample, E> {
+        let meas = self.all::<[f32; 3]>()?;
+        // AK8963 axes differ from accel/gyro ones: x and y are swapped
+        // and z points the other way
+        let mag = [meas.mag[1], meas.mag[0], -meas.mag[2]];
+        Ok(Sample {
+            accel: meas.accel,
+            gyro: meas.gyro,
+            mag: Some(mag),
+            temp_c: meas.temp,
+        })
+    }
+}
+
+pub struct Mpu<DEV, MODE> {
+    mpu: Mpu9250<DEV, MODE>,
+    // crate doesn't tell what it was configured with
+    config: Config,
+}
+
+impl<DEV, MODE> Mpu<DEV, MODE> {
+    // `config` is what `mpu` was created with
+    #[inline]
+    pub fn new(mpu: Mpu9250<DEV, MODE>, config: &Config) -> Self {
+        Mpu {
+            mpu,
+            config: *config,
+        }
+    }
+}
+
+impl<DEV, MODE, E> Imu for Mpu<DEV, MODE>
+where
+    DEV: mpu9250::Device<Error = E>,
+    Mpu9250<DEV, MODE>: Measure<Error = E>,
+{
+    type Error = E;
+
+    #[inline]
+    fn who_am_i(&mut self) -> Result<u8, E> {
+        self.mpu.who_am_i()
+    }
+
+    #[inline]
+    fn sample(&mut self) -> Result<Sample, E> {
+        self.mpu.measure()
+    }
+
+    fn configure(&mut self, config: &Config) -> Result<(), E> {
+        self.mpu.gyro_scale(config.gyro_scale())?;
+        self.mpu.accel_scale(config.accel_scale())?;
+        self.mpu.gyro_temp_data_rate(config.gyro_rate())?;
+        self.mpu.accel_data_rate(config.accel_rate())?;
+        self.mpu.sample_rate_divisor(config.divisor)?;
+        self.config = *config;
+        Ok(())
+    }
+
+    #[inline]
+    fn enable_data_ready(&mut self) -> Result<(), E> {
+        self.mpu
+            .enable_interrupts(mpu9250::InterruptEnable::RAW_RDY_EN)
+    }
+
+    // crate resets only with delay, so settings are written over
+    fn recover(&mut self) -> Result<(), E> {
+        let config = self.config;
+        self.configure(&config)?;
+        self.enable_data_ready()
+    }
+
+    #[inline]
+    fn scales(&self) -> Scales {
+        Scales::new(&self.config)
+    }
+
+    #[inline]
+    fn sample_rate_hz(&self) -> f32 {
+        self.config.sample_rate_hz()
+    }
+
+    // Crate can't excite sensors and keeps the bus, so there is no test
+    // to run; identity was checked when crate was created.
+    #[inline]
+    fn self_test<D>(&mut self, _delay: &mut D) -> Result<Option<bool>, E>
+    where
+        D: DelayMs<u8>,
+    {
+        Ok(None)
+    }
+}
@@ -0,0 +1,106 @@
+// Register access over SPI with chip select; address MSB set for reads.
+use ehal::blocking::spi;
+use ehal::digital::v2::OutputPin;
+
+const READ: u8 = 0x80;
+
+pub struct Registers<SPI, NCS> {
+    spi: SPI,
+    ncs: NCS,
+}
+
+impl<SPI, NCS, E> Registers<SPI, NCS>
+where
+    SPI: spi::Transfer<u8, Error = E>,
+    NCS: OutputPin,
+{
+    #[inline]
+    pub fn new(spi: SPI, ncs: NCS) -> Self {
+        Registers { spi, ncs }
+    }
+
+    #[inline]
+    pub fn release(self) -> (SPI, NCS) {
+        (self.spi, self.ncs)
+    }
+
+    // buffer[0] is register, rest is filled with its contents
+    pub fn read_many(&mut self, buffer: &mut [u8]) -> Result<(), E> {
+        buffer[0] |= READ;
+        self.transfer(buffer)
+    }
+
+    pub fn read(&mut self, reg: u8) -> Result<u8, E> {
+        let mut buffer = [reg, 0];
+        self.read_many(&mut buffer)?;
+        Ok(buffer[1])
+    }
+
+    pub fn write(&mut self, reg: u8, value: u8) -> Result<(), E> {
+        let mut buffer = [reg, value];
+        self.transfer(&mut buffer)
+    }
+
+    pub fn modify<F>(&mut self, reg: u8, f: F) -> Result<(), E>
+    where
+        F: FnOnce(u8) -> u8,
+    {
+        let value = self.read(reg)?;
+        self.write(reg, f(value))
+    }
+
+    fn transfer(&mut self, buffer: &mut [u8]) -> Result<(), E> {
+        self.ncs.set_low().ok();
+        let res = self.spi.transfer(buffer);
+        self.ncs.set_high().ok();
+        res.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock::{Bus, Ncs};
+    use super::*;
+
+    fn registers() -> (Bus, Registers<Bus, Ncs>) {
+        let bus = Bus::new();
+        let regs = Registers::new(bus.clone(), bus.ncs());
+        (bus, regs)
+    }
+
+    #[test]
+    fn read_sets_address_msb() {
+        let (bus, mut regs) = registers();
+        bus.chip().regs[0x75] = 0x71;
+        assert_eq!(regs.read(0x75), Ok(0x71));
+        assert_eq!(bus.chip().frames, [[0xf5, 0]]);
+    }
+
+    #[test]
+    fn write_keeps_address_msb_clear() {
+        let (bus, mut regs) = registers();
+        assert_eq!(regs.write(0x6b, 0x80), Ok(()));
+        assert_eq!(bus.chip().frames, [[0x6b, 0x80]]);
+        assert_eq!(bus.chip().regs[0x6b], 0x80);
+    }
+
+    #[test]
+    fn read_many_is_one_frame() {
+        let (bus, mut regs) = registers();
+        bus.chip().regs[0x3b..0x3e].copy_from_slice(&[1, 2, 3]);
+        let mut buffer = [0x3b, 0, 0, 0];
+        assert_eq!(regs.read_many(&mut buffer), Ok(()));
+        assert_eq!(buffer[1..], [1, 2, 3]);
+        assert_eq!(bus.chip().frames, [[0xbb, 0, 0, 0]]);
+        assert!(!bus.chip().selected);
+    }
+
+    #[test]
+    fn modify_reads_then_writes() {
+        let (bus, mut regs) = registers();
+        bus.chip().regs[0x1a] = 0b1010;
+        assert_eq!(regs.modify(0x1a, |v| v | 1), Ok(()));
+        assert_eq!(bus.chip().frames, [[0x9a, 0], [0x1a, 0b1011]]);
+        assert!(!bus.chip().selected);
+    }
+}
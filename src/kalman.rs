@@ -1,5 +1,34 @@
+// Scalar Kalman filter per tilt axis: angle and gyro bias states,
+// gyro rate drives prediction, accel derived angle is the measurement.
+use core::f32::consts::PI;
+use libm::{atan2f, cosf, fabsf, sinf, sqrtf, tanf};
+
 type Float = f32;
 
+#[derive(Copy, Clone, PartialEq)]
+pub struct Config {
+    // use filter output instead of main estimator for these axes
+    pub roll: bool,
+    pub pitch: bool,
+    // process noise of angle and bias, measurement noise
+    pub q_angle: Float,
+    pub q_bias: Float,
+    pub r: Float,
+}
+
+impl Config {
+    #[inline]
+    pub const fn new() -> Self {
+        Config {
+            roll: false,
+            pitch: false,
+            q_angle: 0.001,
+            q_bias: 0.003,
+            r: 0.03,
+        }
+    }
+}
+
 pub struct AngularKalman {
     q_a: Float,
     q_b: Float,
@@ -14,10 +43,50 @@ pub struct AngularKalman {
 }
 
 impl AngularKalman {
+    #[inline]
+    pub const fn new(q_angle: Float, q_bias: Float, r: Float) -> Self {
+        AngularKalman {
+            q_a: q_angle,
+            q_b: q_bias,
+            r,
+            angle: 0.0,
+            bias: 0.0,
+            rate: 0.0,
+            p: [[0.0; 2]; 2],
+            k: [0.0; 2],
+            y: 0.0,
+            s: 0.0,
+        }
+    }
+
+    #[inline]
+    pub fn set_noises(&mut self, q_angle: Float, q_bias: Float, r: Float) {
+        self.q_a = q_angle;
+        self.q_b = q_bias;
+        self.r = r;
+    }
+
+    // restarts from known angle, e.g. on wrap around
+    #[inline]
+    pub fn set_angle(&mut self, angle: Float) {
+        self.angle = angle;
+    }
+
+    #[inline]
+    pub fn angle(&self) -> Float {
+        self.angle
+    }
+
+    #[inline]
+    pub fn bias(&self) -> Float {
+        self.bias
+    }
+
     pub fn step(&mut self, angle: Float, rate: Float, dt: Float) -> Float {
         self.rate = rate - self.bias;
         self.angle += self.rate * dt;
-        self.p[0][0] += dt * (dt * self.p[1][1] - self.p[0][1] - self.p[1][0] + self.q_a);
+        self.p[0][0] +=
+            dt * (dt * self.p[1][1] - self.p[0][1] - self.p[1][0] + self.q_a);
         self.p[0][1] -= dt * self.p[1][1];
         self.p[1][0] -= dt * self.p[1][1];
         self.p[1][1] += self.q_b * dt;
@@ -30,11 +99,82 @@ impl AngularKalman {
         self.angle += self.k[0] * self.y;
         self.bias += self.k[1] * self.y;
 
-        self.p[0][0] -= self.k[0] * self.p[0][0];
-        self.p[0][1] -= self.k[0] * self.p[0][1];
-        self.p[1][0] -= self.k[1] * self.p[0][0];
-        self.p[1][1] -= self.k[1] * self.p[0][1];
+        let (p00, p01) = (self.p[0][0], self.p[0][1]);
+        self.p[0][0] -= self.k[0] * p00;
+        self.p[0][1] -= self.k[0] * p01;
+        self.p[1][0] -= self.k[1] * p00;
+        self.p[1][1] -= self.k[1] * p01;
 
         return self.angle;
     }
-}
\ No newline at end of file
+}
+
+// Roll and pitch, each with own filter.
+pub struct RollPitch {
+    config: Config,
+    axes: [AngularKalman; 2],
+    initialized: bool,
+}
+
+impl RollPitch {
+    pub fn new() -> Self {
+        let c = Config::new();
+        RollPitch {
+            config: c,
+            axes: [
+                AngularKalman::new(c.q_angle, c.q_bias, c.r),
+                AngularKalman::new(c.q_angle, c.q_bias, c.r),
+            ],
+            initialized: false,
+        }
+    }
+
+    pub fn configure(&mut self, config: &Config) {
+        if self.config == *config {
+            return;
+        }
+        self.config = *config;
+        for f in self.axes.iter_mut() {
+            f.set_noises(config.q_angle, config.q_bias, config.r);
+        }
+    }
+
+    // (roll, pitch) that should come from here instead of estimator
+    #[inline]
+    pub fn overrides(&self) -> (bool, bool) {
+        (self.config.roll, self.config.pitch)
+    }
+
+    // gyro in rad/s; returns [roll, pitch], rad
+    pub fn update(
+        &mut self,
+        accel: &[f32; 3],
+        gyro: &[f32; 3],
+        dt: f32,
+    ) -> [f32; 2] {
+        let roll = atan2f(accel[1], accel[2]);
+        let pitch =
+            atan2f(-accel[0], sqrtf(accel[1] * accel[1] + accel[2] * accel[2]));
+        if !self.initialized {
+            self.axes[0].set_angle(roll);
+            self.axes[1].set_angle(pitch);
+            self.initialized = true;
+        }
+
+        // body rates to Euler angle rates, singular at +-PI/2 pitch
+        let r = self.axes[0].angle();
+        let p = crate::utils::clamp(self.axes[1].angle(), -1.5, 1.5);
+        let (sr, cr) = (sinf(r), cosf(r));
+        let roll_rate = gyro[0] + (sr * gyro[1] + cr * gyro[2]) * tanf(p);
+        let pitch_rate = cr * gyro[1] - sr * gyro[2];
+
+        // accel roll jumps at +-PI, follow it instead of slewing back
+        if fabsf(roll - r) > PI / 2. {
+            self.axes[0].set_angle(roll);
+        }
+        [
+            self.axes[0].step(roll, roll_rate, dt),
+            self.axes[1].step(pitch, pitch_rate, dt),
+        ]
+    }
+}
@@ -0,0 +1,5 @@
+// Parts that don't need the board, kept apart from the firmware so they
+// build and test on host: `make test`.
+#![cfg_attr(not(test), no_std)]
+
+pub mod imu;
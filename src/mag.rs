@@ -0,0 +1,154 @@
+// Magnetometer: hard and soft iron calibration, tilt compensated
+// heading and its fusion with gyro integrated yaw.
+use libm::{atan2f, cosf, sinf};
+
+use crate::types::CalibrationError;
+use crate::utils::wrap_pi;
+
+// at 250Hz, ~2s of rotating
+const MIN_CALIBRATION_SAMPLES: usize = 500;
+// smallest axis radius relative to largest one; lower means
+// not all orientations were visited
+const MIN_RADIUS_RATIO: f32 = 0.5;
+// 1/s, how fast gyro yaw is pulled towards magnetic heading
+const HEADING_GAIN: f32 = 0.5;
+
+// Procedure is explained in https://github.com/kriswiner/MPU6050/wiki/Simple-and-Effective-Magnetometer-Calibration
+#[derive(Copy, Clone, PartialEq)]
+pub struct Calibration {
+    // hard iron
+    pub bias: [f32; 3],
+    // soft iron, diagonal only
+    pub scale: [f32; 3],
+}
+
+impl Calibration {
+    #[inline]
+    pub const fn new() -> Self {
+        Calibration {
+            bias: [0.0, 0.0, 0.0],
+            scale: [1.0, 1.0, 1.0],
+        }
+    }
+
+    #[inline]
+    pub fn apply(&self, raw: &[f32; 3]) -> [f32; 3] {
+        [
+            (raw[0] - self.bias[0]) * self.scale[0],
+            (raw[1] - self.bias[1]) * self.scale[1],
+            (raw[2] - self.bias[2]) * self.scale[2],
+        ]
+    }
+
+    // bx,by,bz,sx,sy,sz
+    #[inline]
+    pub fn results(&self) -> [f32; 6] {
+        [
+            self.bias[0],
+            self.bias[1],
+            self.bias[2],
+            self.scale[0],
+            self.scale[1],
+            self.scale[2],
+        ]
+    }
+}
+
+// Collects extremes per axis while board is rotated through
+// all orientations.
+pub struct Calibrator {
+    min: [f32; 3],
+    max: [f32; 3],
+    samples: usize,
+}
+
+impl Calibrator {
+    #[inline]
+    pub const fn new() -> Self {
+        Calibrator {
+            min: [0.0; 3],
+            max: [0.0; 3],
+            samples: 0,
+        }
+    }
+
+    #[inline]
+    pub fn reset(&mut self) {
+        *self = Calibrator::new();
+    }
+
+    pub fn add(&mut self, raw: &[f32; 3]) {
+        for i in 0..3 {
+            if self.samples == 0 {
+                self.min[i] = raw[i];
+                self.max[i] = raw[i];
+            } else {
+                self.min[i] = self.min[i].min(raw[i]);
+                self.max[i] = self.max[i].max(raw[i]);
+            }
+        }
+        self.samples += 1;
+    }
+
+    pub fn finish(&self) -> Result<Calibration, CalibrationError> {
+        if self.samples < MIN_CALIBRATION_SAMPLES {
+            return Err(CalibrationError::TooFewSamples);
+        }
+        let mut bias = [0.0; 3];
+        let mut radius = [0.0; 3];
+        for i in 0..3 {
+            bias[i] = (self.max[i] + self.min[i]) / 2.;
+            radius[i] = (self.max[i] - self.min[i]) / 2.;
+        }
+        let smallest = radius[0].min(radius[1]).min(radius[2]);
+        let largest = radius[0].max(radius[1]).max(radius[2]);
+        if smallest <= 0. || smallest < largest * MIN_RADIUS_RATIO {
+            return Err(CalibrationError::PoorCoverage);
+        }
+        let avg = (radius[0] + radius[1] + radius[2]) / 3.;
+        Ok(Calibration {
+            bias,
+            scale: [avg / radius[0], avg / radius[1], avg / radius[2]],
+        })
+    }
+}
+
+// Heading of calibrated body frame field, rad; roll and pitch in rad
+pub fn heading(mag: &[f32; 3], roll: f32, pitch: f32) -> f32 {
+    let (sr, cr) = (sinf(roll), cosf(roll));
+    let (sp, cp) = (sinf(pitch), cosf(pitch));
+    // de-rotate into horizontal plane
+    let xh = mag[0] * cp + mag[1] * sr * sp + mag[2] * cr * sp;
+    let yh = mag[1] * cr - mag[2] * sr;
+    atan2f(-yh, xh)
+}
+
+// Complementary filter: gyro yaw for short term, magnetic heading
+// for long term, so yaw doesn't drift and is absolute.
+pub struct HeadingFusion {
+    // added to estimator yaw, rad
+    offset: f32,
+    initialized: bool,
+}
+
+impl HeadingFusion {
+    #[inline]
+    pub const fn new() -> Self {
+        HeadingFusion {
+            offset: 0.0,
+            initialized: false,
+        }
+    }
+
+    pub fn fuse(&mut self, yaw: f32, heading: f32, dt: f32) -> f32 {
+        if !self.initialized {
+            // snap to magnetic heading on first sample
+            self.offset = wrap_pi(heading - yaw);
+            self.initialized = true;
+        } else {
+            let error = wrap_pi(heading - (yaw + self.offset));
+            self.offset = wrap_pi(self.offset + HEADING_GAIN * error * dt);
+        }
+        wrap_pi(yaw + self.offset)
+    }
+}
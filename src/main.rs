@@ -13,6 +13,8 @@
 #![feature(const_impl_trait)]
 
 mod ahrs;
+mod alignment;
+mod autotune;
 #[macro_use]
 mod logging;
 mod blackbox;
@@ -22,12 +24,22 @@ mod chrono;
 mod cmd;
 mod communication;
 mod controllers;
+mod dyn_notch;
+mod estimators;
+mod filters;
+mod health;
+mod kalman;
+mod mag;
 mod mixer;
 mod prelude;
+mod quaternion;
+mod schedule;
 mod spsc;
 mod telemetry;
+mod thermal;
 mod types;
 mod utils;
+mod vibration;
 
 use core::fmt::Write;
 use cortex_m_rt::{exception, ExceptionFrame};
@@ -44,11 +56,18 @@ use rtic::mutex_prelude::TupleExt02;
 
 use boards::*;
 use bootloader::Bootloader;
+use fcfs_rtfm::imu::{self, Imu};
 use mixer::MotorCtrl;
 use prelude::*;
 use telemetry::Telemetry;
 
-#[app(device = crate::boards::mydevice, peripherals = true)]
+// Inner (rate) loop runs on data ready at top priority; outer loop,
+// telemetry and housekeeping are lower priority tasks it preempts.
+#[app(
+    device = crate::boards::mydevice,
+    peripherals = true,
+    dispatchers = [EXTI1, EXTI3]
+)]
 mod app {
     use super::*;
 
@@ -62,7 +81,7 @@ mod app {
         #[task_local]
         extih: hal::exti::BoundInterrupt<MpuIntPin, ExtiNum>,
         #[task_local]
-        ahrs: ahrs::AHRS<Dev, chrono::T>,
+        ahrs: ahrs::AHRS<ImuT, chrono::T>,
         log: &'static mut logging::T,
         #[task_local]
         debug_pin: DebugPinT,
@@ -82,6 +101,9 @@ mod app {
         state: crate::types::State,
         #[init(crate::bootloader::create())]
         bootloader: crate::bootloader::T,
+        #[task_local]
+        ticker: crate::schedule::Ticker,
+        perf: crate::chrono::Perf,
     }
 
     #[init()]
@@ -115,39 +137,113 @@ mod app {
 
         // MPU
         let ncs_pin = conf.ncs.output().push_pull().output_speed(HighSpeed);
-        // 8Hz
-        let gyro_rate = mpu9250::GyroTempDataRate::DlpfConf(mpu9250::Dlpf::_2);
-
-        let mut mpu9250 = Mpu9250::imu_with_reinit(
+        // changed at runtime with `ahrs.configure_imu`
+        let imu_config = imu::Config::new();
+
+        let reinit = |spi: SPI, ncs| {
+            let (dev_spi, (scl, miso, mosi)) = spi.free();
+            let new_spi =
+                dev_spi.spi((scl, miso, mosi), mpu9250::MODE, 20.mhz(), clocks);
+            Some((new_spi, ncs))
+        };
+        #[cfg(all(
+            acquisition = "acquisition_single",
+            sensors = "sensors_imu"
+        ))]
+        let mut imu = imu::mpu::Mpu::new(
+            Mpu9250::imu_with_reinit(
+                spi,
+                ncs_pin,
+                &mut delay,
+                &mut MpuConfig::imu()
+                    .gyro_scale(imu_config.gyro_scale())
+                    .accel_scale(imu_config.accel_scale())
+                    .gyro_temp_data_rate(imu_config.gyro_rate())
+                    .accel_data_rate(imu_config.accel_rate())
+                    .sample_rate_divisor(imu_config.divisor),
+                reinit,
+            )
+            .unwrap(),
+            &imu_config,
+        );
+        #[cfg(all(
+            acquisition = "acquisition_single",
+            sensors = "sensors_marg"
+        ))]
+        let mut imu = imu::mpu::Mpu::new(
+            Mpu9250::marg_with_reinit(
+                spi,
+                ncs_pin,
+                &mut delay,
+                &mut MpuConfig::marg()
+                    .gyro_scale(imu_config.gyro_scale())
+                    .accel_scale(imu_config.accel_scale())
+                    .gyro_temp_data_rate(imu_config.gyro_rate())
+                    .accel_data_rate(imu_config.accel_rate())
+                    .sample_rate_divisor(imu_config.divisor),
+                reinit,
+            )
+            .unwrap(),
+            &imu_config,
+        );
+        #[cfg(all(acquisition = "acquisition_fifo", imu = "imu_mpu9250"))]
+        let mut imu = imu::invensense::Invensense::with_reinit(
             spi,
             ncs_pin,
             &mut delay,
-            &mut MpuConfig::imu()
-                .gyro_temp_data_rate(gyro_rate)
-                .sample_rate_divisor(3),
-            |spi, ncs| {
-                let (dev_spi, (scl, miso, mosi)) = spi.free();
-                let new_spi = dev_spi.spi(
-                    (scl, miso, mosi),
-                    mpu9250::MODE,
-                    20.mhz(),
-                    clocks,
-                );
-                Some((new_spi, ncs))
-            },
+            imu::invensense::Variant::Mpu9250,
+            &imu_config,
+            reinit,
+        )
+        .unwrap();
+        #[cfg(imu = "imu_icm20602")]
+        let mut imu = imu::invensense::Invensense::with_reinit(
+            spi,
+            ncs_pin,
+            &mut delay,
+            imu::invensense::Variant::Icm20602,
+            &imu_config,
+            reinit,
+        )
+        .unwrap();
+        #[cfg(imu = "imu_icm42688")]
+        let mut imu = imu::icm42688::Icm42688::with_reinit(
+            spi,
+            ncs_pin,
+            &mut delay,
+            &imu_config,
+            reinit,
         )
         .unwrap();
-        info!(log, "mpu ok");
+        info!(log, "imu ok");
 
-        mpu9250
-            .enable_interrupts(mpu9250::InterruptEnable::RAW_RDY_EN)
-            .unwrap();
+        // data ready triggers the loop, with FIFO samples come from there
+        imu.enable_data_ready().unwrap();
         info!(log, "int enabled; ");
+        match imu.self_test(&mut delay) {
+            Ok(Some(true)) => info!(log, "self-test ok"),
+            Ok(None) => info!(log, "self-test not supported"),
+            _ => error!(log, "imu self-test failed\r\n"),
+        }
 
-        info!(log, "now: {:?}", mpu9250.get_enabled_interrupts());
+        // cycle counter runs only with trace enabled
+        let mut dcb = ctx.core.DCB;
+        dcb.enable_trace();
+        let mut dwt = ctx.core.DWT;
+        dwt.enable_cycle_counter();
+        let perf = chrono::Perf::new(clocks.sysclk());
         let mut chrono = chrono::rtfm_stopwatch(clocks.sysclk());
-        let mut ahrs = ahrs::AHRS::create(mpu9250, &mut delay, chrono);
-        info!(log, "ahrs ok");
+        let mut ahrs =
+            match ahrs::AHRS::create(imu, &imu_config, &mut delay, chrono) {
+                Ok(ahrs) => ahrs,
+                Err(_) => panic!("ahrs: bus error"),
+            };
+        // motors stay off until `restcal` succeeds
+        if ahrs.calibrated() {
+            info!(log, "ahrs ok");
+        } else {
+            error!(log, "ahrs: calibration at rest rejected\r\n");
+        }
         // motors
         let motors = boards::setup_motors(
             conf.motor_pins,
@@ -157,12 +253,18 @@ mod app {
         );
 
         info!(log, "ready");
-        ahrs.setup_time();
+        ahrs.setup_time().unwrap();
 
         let (producer, consumer) = spsc::pipe();
         let channel = communication::channel(conf.tx_ch, tx);
         let new_channel =
             channel.send(|b| utils::fill_with_str(b, "channel ok\r\n"));
+        // changed at runtime from `Control::schedule`
+        let ticker = schedule::Ticker::new(
+            ctx.core.SYST,
+            clocks.sysclk().0,
+            schedule::Config::new().housekeeping_hz,
+        );
         info!(log, "done init");
 
         (
@@ -176,22 +278,41 @@ mod app {
                 producer,
                 consumer,
                 motors,
+                ticker,
+                perf,
             },
             init::Monotonics(),
         )
     }
 
-    #[idle(resources=[consumer, control, channel, bootloader])]
+    #[idle(resources=[consumer, control, state, channel, bootloader, perf])]
     fn idle(mut ctx: idle::Context) -> ! {
         static mut CMD: cmd::Cmd = cmd::create();
+        static mut LOAD: chrono::Load = chrono::Load::new();
         static TELE: telemetry::Telemetry = telemetry::create();
         let idle::Resources {
             mut consumer,
             mut channel,
             mut control,
+            mut state,
             mut bootloader,
+            mut perf,
         } = ctx.resources;
+        // sent as soon as channel is free, after status frame
+        let mut perf_report = false;
         loop {
+            if let Some(load) = LOAD.tick(chrono::now()) {
+                perf.lock(|p| p.set_load(load));
+            }
+            if perf_report
+                && channel.lock(|c| c.as_ref().map_or(false, |c| c.is_ready()))
+            {
+                let current_perf = perf.lock(|p| *p);
+                communication::send_shared(&mut channel, |ch| {
+                    TELE.perf(&current_perf, ch)
+                });
+                perf_report = false;
+            }
             let maybe_byte = consumer.dequeue();
 
             if let Some(byte) = maybe_byte {
@@ -201,13 +322,68 @@ mod app {
                 });
                 match requests {
                     Some(types::Requests::Status) => {
-                        channel.lock(|shared_channel| {
-                            let maybe_channel = shared_channel.take();
-                            if let Some(channel) = maybe_channel {
-                                let new_channel =
-                                    TELE.control(&current_control, channel);
-                                *shared_channel = Some(new_channel);
-                            }
+                        communication::send_shared(&mut channel, |ch| {
+                            TELE.control(&current_control, ch)
+                        });
+                        perf_report = true;
+                    }
+                    Some(types::Requests::Perf) => {
+                        perf_report = true;
+                    }
+                    Some(types::Requests::PerfReset) => {
+                        perf.lock(|p| p.reset());
+                    }
+                    Some(types::Requests::Autotune) => {
+                        let report = state.lock(|s| s.autotune);
+                        communication::send_shared(&mut channel, |ch| {
+                            TELE.autotune(&report, ch)
+                        });
+                    }
+                    Some(types::Requests::AutotuneAccept) => {
+                        let report = state.lock(|s| s.autotune);
+                        if report.done && report.ku > 0. {
+                            control.lock(|c| {
+                                c.rate[report.axis] = report.gains;
+                                c.autotune.axis = None;
+                            });
+                        }
+                    }
+                    Some(types::Requests::Mag) => {
+                        let status = state.lock(|s| s.mag_calibration);
+                        communication::send_shared(&mut channel, |ch| {
+                            TELE.mag(&current_control.mag, &status, ch)
+                        });
+                    }
+                    Some(types::Requests::Imu) => {
+                        let current_state = state.lock(|s| *s);
+                        communication::send_shared(&mut channel, |ch| {
+                            TELE.imu(&current_control.imu, &current_state, ch)
+                        });
+                    }
+                    Some(types::Requests::GyroTemp) => {
+                        let current_state = state.lock(|s| *s);
+                        communication::send_shared(&mut channel, |ch| {
+                            TELE.gyro_temp(
+                                &current_control.gyro_temp,
+                                &current_state,
+                                ch,
+                            )
+                        });
+                    }
+                    Some(types::Requests::Schedule) => {
+                        let current_state = state.lock(|s| *s);
+                        communication::send_shared(&mut channel, |ch| {
+                            TELE.schedule(
+                                &current_control.schedule,
+                                &current_state,
+                                ch,
+                            )
+                        });
+                    }
+                    Some(types::Requests::Health) => {
+                        let counters = state.lock(|s| s.health);
+                        communication::send_shared(&mut channel, |ch| {
+                            TELE.health(&counters, ch)
                         });
                     }
                     Some(types::Requests::Boot) => {
@@ -222,7 +398,8 @@ mod app {
         }
     }
 
-    #[task(binds=USART2_EXTI26, resources = [rx, producer, log])]
+    // above telemetry formatting, so no byte is overrun
+    #[task(binds=USART2_EXTI26, priority = 2, resources = [rx, producer, log])]
     fn handle_rx(mut ctx: handle_rx::Context) {
         let handle_rx::Resources {
             mut rx,
@@ -241,60 +418,245 @@ mod app {
         }
     }
 
+    // Inner loop, on every sample: estimation, sensor health, rate PID and
+    // motors. Nothing here waits on logging or serial.
     #[task(binds=[("configuration_drone", EXTI15_10),
                   ("configuration_dev", EXTI0)],
-           resources = [extih, ahrs, log, debug_pin,
-                        channel, control, state, motors])]
+           priority = 3,
+           resources = [extih, ahrs, debug_pin, control, state, motors,
+                        perf])]
     fn handle_mpu(mut ctx: handle_mpu::Context) {
-        static TELE: telemetry::Telemetry = telemetry::create();
+        static mut BODY_RATE: controllers::BodyRate = controllers::create();
+        static mut AUTOTUNE: autotune::Relay = autotune::create();
+        static mut OUTER: schedule::Divider = schedule::Divider::new();
+        static mut TELEMETRY: schedule::Divider = schedule::Divider::new();
+        let start = chrono::now();
         let mut debug_pin = ctx.resources.debug_pin;
         let mut ahrs = ctx.resources.ahrs;
         let mut state = ctx.resources.state.lock(|s| s.clone());
-        let mut log = ctx.resources.log;
         let mut motors = ctx.resources.motors;
-        let mut channel = ctx.resources.channel;
         let mut extih = ctx.resources.extih;
         let control = ctx.resources.control.lock(|c| c.clone());
 
+        // counted by health monitor as bus error
+        ahrs.configure_imu(&control.imu).ok();
+        ahrs.configure_alignment(&control.alignment);
+        ahrs.configure_filters(&control.filters);
+        ahrs.configure_estimator(&control.estimator);
+        ahrs.configure_vibration(&control.vibration);
+        ahrs.configure_mag(&control.mag);
+        if let Some(result) = ahrs.calibrate_mag(control.mag_calibrating) {
+            // rejected run keeps previous calibration
+            if let Ok(calibration) = result {
+                ctx.resources.control.lock(|c| c.mag = calibration);
+            }
+            let status = types::CalibrationStatus::from_result(&result);
+            state.mag_calibration = status;
+            ctx.resources.state.lock(|s| s.mag_calibration = status);
+        }
+        ahrs.configure_gyro_temp(&control.gyro_temp);
+        if let Some(result) =
+            ahrs.calibrate_gyro_temp(control.gyro_temp_calibrating)
+        {
+            if let Ok(model) = result {
+                ctx.resources.control.lock(|c| c.gyro_temp = model);
+            }
+            let status = types::CalibrationStatus::from_result(&result);
+            state.gyro_temp_calibration = status;
+            ctx.resources
+                .state
+                .lock(|s| s.gyro_temp_calibration = status);
+        }
+        if ahrs.calibrate_at_rest(control.rest_calibrating).is_some() {
+            ctx.resources.control.lock(|c| c.rest_calibrating = false);
+        }
         let estimation = ahrs.estimate();
+        if control.clear_failsafe {
+            ahrs.clear_failsafe();
+            ctx.resources.control.lock(|c| c.clear_failsafe = false);
+        }
+        let health = ahrs.supervise(&control.health);
+        state.health = health;
+        let rest_calibration = ahrs.rest_calibration();
+        state.rest_calibration = rest_calibration;
+        // failsafe and bus errors still count, they are not a stall
+        state.runs = state.runs.wrapping_add(1);
+        let runs = state.runs;
+        ctx.resources.state.lock(|s| {
+            s.health = health;
+            s.rest_calibration = rest_calibration;
+            s.runs = runs;
+        });
         match estimation {
+            // sensor can't be trusted until failsafe is cleared
+            _ if health.failsafe => {
+                BODY_RATE.reset();
+                let thrust =
+                    control.health.failsafe.thrust(control.idle_thrust);
+                motors.set_duty(0., 0., 0., thrust);
+            }
+            // biases aren't known or are being taken
+            _ if !ahrs.calibrated() => {
+                BODY_RATE.reset();
+                motors.set_duty(0., 0., 0., 0.);
+            }
+            // woken for a sample that was already drained
+            Ok(ref result) if result.samples == 0 => {}
             Ok(result) => {
                 state.ahrs = result;
-                let (cmd, errors) = controllers::body_rate(&state, &control);
+                state.notch_hz = ahrs.notch_peaks();
+                state.vibration = ahrs.vibration();
+                // setpoint and tpa come from outer loop
+                let (mut cmd, errors) =
+                    BODY_RATE.update(&state.setpoint, &state, &control);
+                AUTOTUNE.configure(&control.autotune);
+                if control.autotune_restart {
+                    AUTOTUNE.restart();
+                    ctx.resources.control.lock(|c| c.autotune_restart = false);
+                }
+                // rate PID takes the axis back once relay is done
+                if let Some(axis) = control.autotune.axis {
+                    let running = !AUTOTUNE.report().done;
+                    if running && control.thrust > control.idle_thrust {
+                        BODY_RATE.reset_axis(axis);
+                        cmd[axis] = AUTOTUNE.update(
+                            errors[axis],
+                            result.biased_gyro[axis],
+                            result.dt_s,
+                        );
+                    }
+                }
+                state.autotune = AUTOTUNE.report();
                 state.errors = errors;
                 state.cmd = cmd;
+                state.saturated =
+                    motors.set_duty(cmd[0], cmd[1], cmd[2], control.thrust);
                 ctx.resources.state.lock(|s| {
                     *s = state;
                 });
 
-                motors.set_duty(cmd[0], cmd[1], cmd[2], control.thrust);
-
-                if control.telemetry {
-                    channel.lock(|maybe_channel| {
-                        if let Some(in_channel) = maybe_channel.take() {
-                            let new_channel = TELE.state(&state, in_channel);
-                            *maybe_channel = Some(new_channel);
-                        }
-                    });
+                // still busy with previous step: this one is skipped
+                if OUTER.tick(control.schedule.outer_divider) {
+                    outer_loop::spawn().ok();
+                }
+                if TELEMETRY.tick(control.schedule.telemetry_divider) {
+                    send_telemetry::spawn().ok();
                 }
-
-                log.lock(|l| {
-                    debugfloats!(
-                        l,
-                        ":",
-                        result.ypr.yaw,
-                        result.ypr.pitch,
-                        result.ypr.roll
-                    )
-                });
-            }
-            Err(_e) => {
-                log.lock(|l| error!(l, "err"));
             }
+            // counted by health monitor
+            Err(_e) => {}
         };
 
         debug_pin.set_low();
         extih.unpend();
+        let end = chrono::now();
+        ctx.resources.perf.lock(|p| {
+            p.step(start);
+            p.task(chrono::Task::Inner, start, end);
+        });
+    }
+
+    // Outer (attitude) loop: body rate setpoint and throttle attenuation
+    // for following inner loop steps.
+    #[task(priority = 2, resources = [control, state, perf])]
+    fn outer_loop(mut ctx: outer_loop::Context) {
+        let start = chrono::now();
+        let control = ctx.resources.control.lock(|c| c.clone());
+        let state = ctx.resources.state.lock(|s| s.clone());
+        let setpoint = controllers::setpoint(&state, &control);
+        let tpa = controllers::tpa(&control);
+        if let Some(heading) = controllers::turned_heading(&state, &control) {
+            ctx.resources
+                .control
+                .lock(|c| c.target_degrees.yaw = heading);
+        }
+        ctx.resources.state.lock(|s| {
+            s.setpoint = setpoint;
+            s.tpa = tpa;
+        });
+        let end = chrono::now();
+        ctx.resources
+            .perf
+            .lock(|p| p.task(chrono::Task::Outer, start, end));
+    }
+
+    // Telemetry frames and attitude log, formatting is slow
+    #[task(priority = 1, resources = [control, state, channel, log, perf])]
+    fn send_telemetry(mut ctx: send_telemetry::Context) {
+        static TELE: telemetry::Telemetry = telemetry::create();
+        static mut TM_TICK: usize = 0;
+        let start = chrono::now();
+        let send_telemetry::Resources {
+            mut control,
+            mut state,
+            mut channel,
+            mut log,
+            mut perf,
+        } = ctx.resources;
+        let enabled = control.lock(|c| c.telemetry);
+        let state = state.lock(|s| *s);
+        if enabled {
+            let frame = telemetry::frame(*TM_TICK);
+            *TM_TICK = TM_TICK.wrapping_add(1);
+            communication::send_shared(&mut channel, |ch| {
+                TELE.frame(frame, &state, ch)
+            });
+        }
+
+        log.lock(|l| {
+            debugfloats!(
+                l,
+                ":",
+                state.ahrs.ypr.yaw,
+                state.ahrs.ypr.pitch,
+                state.ahrs.ypr.roll
+            )
+        });
+        let end = chrono::now();
+        perf.lock(|p| p.task(chrono::Task::Telemetry, start, end));
+    }
+
+    // A few times a second: task rates, sensor health reports and
+    // watching that inner loop still runs. Boards have no battery sense
+    // or status LED yet.
+    #[task(binds = SysTick,
+           priority = 1,
+           resources = [ticker, control, state, log, perf])]
+    fn housekeeping(mut ctx: housekeeping::Context) {
+        static mut FAILSAFE: bool = false;
+        static mut RUNS: u32 = 0;
+        static mut STALLED: bool = false;
+        let start = chrono::now();
+        let housekeeping::Resources {
+            mut ticker,
+            mut control,
+            mut state,
+            mut log,
+            mut perf,
+        } = ctx.resources;
+        let schedule = control.lock(|c| c.schedule);
+        ticker.configure(schedule.housekeeping_hz);
+
+        let (health, runs) = state.lock(|s| (s.health, s.runs));
+        if health.failsafe && !*FAILSAFE {
+            log.lock(|l| error!(l, "imu failsafe"));
+        }
+        *FAILSAFE = health.failsafe;
+
+        // Sensor that stopped signalling data ready is read anyway, so
+        // health monitor gets to see it failing.
+        let stalled = runs == *RUNS;
+        if stalled {
+            if !*STALLED {
+                log.lock(|l| error!(l, "imu loop stalled"));
+            }
+            rtic::pend(boards::IMU_INTERRUPT);
+        }
+        *STALLED = stalled;
+        // run pended here doesn't show that loop is alive
+        *RUNS = runs.wrapping_add(stalled as u32);
+        let end = chrono::now();
+        perf.lock(|p| p.task(chrono::Task::Housekeeping, start, end));
     }
 }
 
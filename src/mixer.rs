@@ -1,13 +1,18 @@
 use crate::boards::*;
+use crate::utils::clamp;
 use hal::timer;
+use libm::fabsf;
 
 pub trait MotorCtrl {
-    fn set_duty(&mut self, x: f32, y: f32, z: f32, thrust: f32);
+    // returns true if any of the motors is saturated
+    fn set_duty(&mut self, x: f32, y: f32, z: f32, thrust: f32) -> bool;
 }
 
 impl MotorCtrl for () {
     // dummy
-    fn set_duty(&mut self, x: f32, y: f32, z: f32, thrust: f32) {}
+    fn set_duty(&mut self, x: f32, y: f32, z: f32, thrust: f32) -> bool {
+        false
+    }
 }
 
 pub struct Mixer<M, P> {
@@ -31,16 +36,20 @@ macro_rules! impl_motor_ctrl {
 
         impl<$($pin),+> MotorCtrl for Mixer<$map, ($($pin),+)>
         where $($pin: ehal::PwmPin<Duty = u32>),+ {
-            fn set_duty(&mut self, x: f32, y: f32, z: f32, thrust: f32) {
+            fn set_duty(&mut self, x: f32, y: f32, z: f32, thrust: f32) -> bool {
                 // let duty = self.map * Ctrl::new(x, y, z, thrust);
                 let max_duty = self.max_duty;
+                let z = limit_yaw(&self.map, x, y, z, thrust, max_duty);
+                let mut saturated = false;
                 $(
                     {
                         let row = self.map[$nr];
                         let iduty = row[0] * x + row[1] * y + row[2] * z + row[3] * thrust;
+                        saturated |= iduty < 0.0 || iduty > max_duty;
                         self.pin.$nr.set_duty(clamp(iduty, 0.0, max_duty) as u32);
                     }
                 )+
+                saturated
             }
         }
     )
@@ -49,15 +58,31 @@ macro_rules! impl_motor_ctrl {
 impl_motor_ctrl!(Map4, 4, A 0 B 1 C 2 D 3);
 impl_motor_ctrl!(Map6, 6, A 0 B 1 C 2 D 3 E 4 F 5);
 
-#[inline]
-fn clamp<T: PartialOrd>(val: T, min: T, max: T) -> T {
-    if val > min {
-        if val < max {
-            val
+// Yaw gets only the authority left after roll, pitch and thrust,
+// so it can't steal it from them.
+fn limit_yaw(
+    map: &[[f32; 4]],
+    x: f32,
+    y: f32,
+    z: f32,
+    thrust: f32,
+    max_duty: f32,
+) -> f32 {
+    let mut limit = max_duty;
+    for row in map {
+        if row[2] == 0.0 {
+            continue;
+        }
+        let base = row[0] * x + row[1] * y + row[3] * thrust;
+        let headroom = if row[2] * z > 0.0 {
+            max_duty - base
         } else {
-            max
+            base
+        };
+        let row_limit = headroom.max(0.0) / fabsf(row[2]);
+        if row_limit < limit {
+            limit = row_limit;
         }
-    } else {
-        min
     }
+    clamp(z, -limit, limit)
 }
@@ -0,0 +1,204 @@
+// Unit quaternion attitude, body to earth frame, ZYX Euler convention.
+use core::ops::{Add, Mul};
+use libm::{asinf, atan2f, cosf, sinf, sqrtf};
+
+use crate::prelude::*;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Quaternion {
+    pub w: f32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Quaternion {
+    #[inline]
+    pub const fn new(w: f32, x: f32, y: f32, z: f32) -> Self {
+        Quaternion { w, x, y, z }
+    }
+
+    #[inline]
+    pub const fn identity() -> Self {
+        Quaternion::new(1.0, 0.0, 0.0, 0.0)
+    }
+
+    pub fn from_euler(yaw: f32, pitch: f32, roll: f32) -> Self {
+        let (sy, cy) = (sinf(yaw / 2.), cosf(yaw / 2.));
+        let (sp, cp) = (sinf(pitch / 2.), cosf(pitch / 2.));
+        let (sr, cr) = (sinf(roll / 2.), cosf(roll / 2.));
+        Quaternion::new(
+            cr * cp * cy + sr * sp * sy,
+            sr * cp * cy - cr * sp * sy,
+            cr * sp * cy + sr * cp * sy,
+            cr * cp * sy - sr * sp * cy,
+        )
+    }
+
+    pub fn to_euler(&self) -> EulerAngles {
+        let (w, x, y, z) = (self.w, self.x, self.y, self.z);
+        let sin_pitch = crate::utils::clamp(2. * (w * y - x * z), -1., 1.);
+        EulerAngles {
+            yaw: atan2f(2. * (w * z + x * y), 1. - 2. * (y * y + z * z)),
+            pitch: asinf(sin_pitch),
+            roll: atan2f(2. * (w * x + y * z), 1. - 2. * (x * x + y * y)),
+        }
+    }
+
+    // body to earth rotation matrix, row major
+    pub fn to_matrix(&self) -> [[f32; 3]; 3] {
+        let (w, x, y, z) = (self.w, self.x, self.y, self.z);
+        [
+            [
+                1. - 2. * (y * y + z * z),
+                2. * (x * y - w * z),
+                2. * (x * z + w * y),
+            ],
+            [
+                2. * (x * y + w * z),
+                1. - 2. * (x * x + z * z),
+                2. * (y * z - w * x),
+            ],
+            [
+                2. * (x * z - w * y),
+                2. * (y * z + w * x),
+                1. - 2. * (x * x + y * y),
+            ],
+        ]
+    }
+
+    // Shepperd's method, branch on largest diagonal for precision
+    pub fn from_matrix(m: &[[f32; 3]; 3]) -> Self {
+        let trace = m[0][0] + m[1][1] + m[2][2];
+        let q = if trace > 0. {
+            let s = 2. * sqrtf(1. + trace);
+            Quaternion::new(
+                s / 4.,
+                (m[2][1] - m[1][2]) / s,
+                (m[0][2] - m[2][0]) / s,
+                (m[1][0] - m[0][1]) / s,
+            )
+        } else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+            let s = 2. * sqrtf(1. + m[0][0] - m[1][1] - m[2][2]);
+            Quaternion::new(
+                (m[2][1] - m[1][2]) / s,
+                s / 4.,
+                (m[0][1] + m[1][0]) / s,
+                (m[0][2] + m[2][0]) / s,
+            )
+        } else if m[1][1] > m[2][2] {
+            let s = 2. * sqrtf(1. + m[1][1] - m[0][0] - m[2][2]);
+            Quaternion::new(
+                (m[0][2] - m[2][0]) / s,
+                (m[0][1] + m[1][0]) / s,
+                s / 4.,
+                (m[1][2] + m[2][1]) / s,
+            )
+        } else {
+            let s = 2. * sqrtf(1. + m[2][2] - m[0][0] - m[1][1]);
+            Quaternion::new(
+                (m[1][0] - m[0][1]) / s,
+                (m[0][2] + m[2][0]) / s,
+                (m[1][2] + m[2][1]) / s,
+                s / 4.,
+            )
+        };
+        q.normalized().unwrap_or_else(Quaternion::identity)
+    }
+
+    #[inline]
+    pub fn conjugate(&self) -> Self {
+        Quaternion::new(self.w, -self.x, -self.y, -self.z)
+    }
+
+    #[inline]
+    pub fn norm(&self) -> f32 {
+        sqrtf(
+            self.w * self.w
+                + self.x * self.x
+                + self.y * self.y
+                + self.z * self.z,
+        )
+    }
+
+    #[inline]
+    pub fn normalized(&self) -> Option<Self> {
+        let norm = self.norm();
+        if norm > 0. {
+            Some(self.scaled(1. / norm))
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn scaled(&self, k: f32) -> Self {
+        Quaternion::new(self.w * k, self.x * k, self.y * k, self.z * k)
+    }
+
+    // time derivative when rotating with body rates, rad/s
+    #[inline]
+    pub fn derivative(&self, rates: &[f32; 3]) -> Self {
+        (*self * Quaternion::new(0., rates[0], rates[1], rates[2])).scaled(0.5)
+    }
+
+    // first order integration of body rates over dt, renormalized
+    pub fn integrate(&self, rates: &[f32; 3], dt: f32) -> Self {
+        let q = *self + self.derivative(rates).scaled(dt);
+        q.normalized().unwrap_or_else(Quaternion::identity)
+    }
+
+    // earth frame vector seen in body frame
+    pub fn to_body(&self, v: &[f32; 3]) -> [f32; 3] {
+        let m = self.to_matrix();
+        [
+            m[0][0] * v[0] + m[1][0] * v[1] + m[2][0] * v[2],
+            m[0][1] * v[0] + m[1][1] * v[1] + m[2][1] * v[2],
+            m[0][2] * v[0] + m[1][2] * v[1] + m[2][2] * v[2],
+        ]
+    }
+
+    // body frame vector seen in earth frame
+    #[inline]
+    pub fn to_earth(&self, v: &[f32; 3]) -> [f32; 3] {
+        self.conjugate().to_body(v)
+    }
+
+    // Rotation from self to target in body frame, as axis times angle,
+    // rad; always the short way around.
+    pub fn error_to(&self, target: &Quaternion) -> [f32; 3] {
+        let mut e = self.conjugate() * *target;
+        if e.w < 0. {
+            e = e.scaled(-1.);
+        }
+        let sin_half = sqrtf(e.x * e.x + e.y * e.y + e.z * e.z);
+        if sin_half <= 0. {
+            return [0.0; 3];
+        }
+        let k = 2. * atan2f(sin_half, e.w) / sin_half;
+        [e.x * k, e.y * k, e.z * k]
+    }
+}
+
+impl Add for Quaternion {
+    type Output = Quaternion;
+
+    #[inline]
+    fn add(self, o: Quaternion) -> Quaternion {
+        Quaternion::new(self.w + o.w, self.x + o.x, self.y + o.y, self.z + o.z)
+    }
+}
+
+impl Mul for Quaternion {
+    type Output = Quaternion;
+
+    #[inline]
+    fn mul(self, o: Quaternion) -> Quaternion {
+        Quaternion::new(
+            self.w * o.w - self.x * o.x - self.y * o.y - self.z * o.z,
+            self.w * o.x + self.x * o.w + self.y * o.z - self.z * o.y,
+            self.w * o.y - self.x * o.z + self.y * o.w + self.z * o.x,
+            self.w * o.z + self.x * o.y - self.y * o.x + self.z * o.w,
+        )
+    }
+}
@@ -0,0 +1,101 @@
+// Rates of tasks split off IMU loop. Inner (rate) loop runs on every
+// sample, outer loop and telemetry on every n-th inner step, housekeeping
+// is ticked by SysTick.
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m::peripheral::SYST;
+
+// SysTick reload is 24 bits, so at 64MHz ticks can't be slower
+const MIN_HOUSEKEEPING_HZ: u32 = 4;
+const MAX_HOUSEKEEPING_HZ: u32 = 100;
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Config {
+    // inner loop steps per step of a task, at least 1
+    pub outer_divider: u32,
+    pub telemetry_divider: u32,
+    pub housekeeping_hz: u32,
+}
+
+impl Config {
+    #[inline]
+    pub const fn new() -> Self {
+        Config {
+            outer_divider: 2,
+            telemetry_divider: 2,
+            housekeeping_hz: 5,
+        }
+    }
+
+    #[inline]
+    pub fn valid_housekeeping_hz(hz: u32) -> bool {
+        hz >= MIN_HOUSEKEEPING_HZ && hz <= MAX_HOUSEKEEPING_HZ
+    }
+
+    // outer_div,telemetry_div,housekeeping_hz,outer_hz,telemetry_hz
+    #[inline]
+    pub fn results(&self, loop_hz: f32) -> [f32; 5] {
+        [
+            self.outer_divider as f32,
+            self.telemetry_divider as f32,
+            self.housekeeping_hz as f32,
+            loop_hz / self.outer_divider as f32,
+            loop_hz / self.telemetry_divider as f32,
+        ]
+    }
+}
+
+// counts inner loop steps for a task that runs every `divider` of them
+pub struct Divider {
+    count: u32,
+}
+
+impl Divider {
+    #[inline]
+    pub const fn new() -> Self {
+        Divider { count: 0 }
+    }
+
+    // true when task is due in this step
+    #[inline]
+    pub fn tick(&mut self, divider: u32) -> bool {
+        self.count += 1;
+        if self.count >= divider {
+            self.count = 0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// SysTick driving housekeeping, its rate changes at runtime
+pub struct Ticker {
+    syst: SYST,
+    sysclk_hz: u32,
+    hz: u32,
+}
+
+impl Ticker {
+    pub fn new(mut syst: SYST, sysclk_hz: u32, hz: u32) -> Self {
+        syst.set_clock_source(SystClkSource::Core);
+        let mut ticker = Ticker {
+            syst,
+            sysclk_hz,
+            hz: 0,
+        };
+        ticker.configure(hz);
+        ticker.syst.enable_interrupt();
+        ticker.syst.enable_counter();
+        ticker
+    }
+
+    // cheap when rate is the same
+    pub fn configure(&mut self, hz: u32) {
+        if hz == self.hz || !Config::valid_housekeeping_hz(hz) {
+            return;
+        }
+        self.syst.set_reload(self.sysclk_hz / hz - 1);
+        self.syst.clear_current();
+        self.hz = hz;
+    }
+}
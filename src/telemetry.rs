@@ -1,4 +1,11 @@
+use crate::autotune;
+use crate::chrono;
 use crate::communication::{Channel, TxBuffer};
+use crate::health;
+use crate::imu;
+use crate::mag;
+use crate::schedule;
+use crate::thermal;
 use crate::types;
 
 pub struct Telemetry;
@@ -7,22 +14,230 @@ pub const fn create() -> Telemetry {
     Telemetry
 }
 
+#[derive(Copy, Clone)]
+pub enum Frame {
+    State,
+    Controller,
+    Notch,
+    Kalman,
+    Covariance,
+    Vibration,
+}
+
+// sent in between state frames, round robin
+const AUX_FRAMES: [Frame; 5] = [
+    Frame::Controller,
+    Frame::Notch,
+    Frame::Kalman,
+    Frame::Covariance,
+    Frame::Vibration,
+];
+
+// state goes every other tick, the rest of frames share the others
+#[inline]
+pub fn frame(tick: usize) -> Frame {
+    if tick % 2 == 0 {
+        Frame::State
+    } else {
+        AUX_FRAMES[(tick / 2) % AUX_FRAMES.len()]
+    }
+}
+
+fn fill_with_floats<'a, I>(buffer: &mut TxBuffer, prefix: &[u8], floats: I)
+where
+    I: Iterator<Item = &'a f32>,
+{
+    buffer.extend_from_slice(prefix);
+    buffer.push(b':');
+    for f in floats {
+        let mut b = ryu::Buffer::new();
+        let s = b.format(*f);
+        buffer.extend_from_slice(s.as_bytes());
+        buffer.push(b';');
+    }
+    buffer.push(b'\n');
+}
+
 // XXX: ufmt
 impl Telemetry {
+    #[inline]
+    pub fn frame(
+        &self,
+        frame: Frame,
+        state: &types::State,
+        channel: Channel,
+    ) -> Channel {
+        match frame {
+            Frame::State => self.state(state, channel),
+            Frame::Controller => self.controller(state, channel),
+            Frame::Notch => self.notch(state, channel),
+            Frame::Kalman => self.kalman(state, channel),
+            Frame::Covariance => self.covariance(state, channel),
+            Frame::Vibration => self.vibration(state, channel),
+        }
+    }
+
     #[inline]
     pub fn state(&self, state: &types::State, channel: Channel) -> Channel {
         channel.send(|buffer| {
             // tm:ax,ay,az,gx,gy,gz,dt_s,y,p,r,cx,cy,cz
-            buffer.push(b't');
-            buffer.push(b'm');
-            buffer.push(b':');
-            for f in state.ahrs.short_results().iter().chain(state.cmd.iter()) {
-                let mut b = ryu::Buffer::new();
-                let s = b.format(*f);
-                buffer.extend_from_slice(s.as_bytes());
-                buffer.push(b';');
-            }
-            buffer.push(b'\n');
+            let results = state.ahrs.short_results();
+            let floats = results.iter().chain(state.cmd.iter());
+            fill_with_floats(buffer, b"tm", floats);
+        })
+    }
+
+    #[inline]
+    pub fn controller(
+        &self,
+        state: &types::State,
+        channel: Channel,
+    ) -> Channel {
+        channel.send(|buffer| {
+            // cs:spx,spy,spz,tpa,saturated
+            let extra = [state.tpa, state.saturated as u8 as f32];
+            let floats = state.setpoint.iter().chain(extra.iter());
+            fill_with_floats(buffer, b"cs", floats);
+        })
+    }
+
+    #[inline]
+    pub fn notch(&self, state: &types::State, channel: Channel) -> Channel {
+        channel.send(|buffer| {
+            // dn:x1,x2,x3,y1,y2,y3,z1,z2,z3
+            let floats = state.notch_hz.iter().flat_map(|axis| axis.iter());
+            fill_with_floats(buffer, b"dn", floats);
+        })
+    }
+
+    #[inline]
+    pub fn kalman(&self, state: &types::State, channel: Channel) -> Channel {
+        channel.send(|buffer| {
+            // kf:est_roll,est_pitch,kf_roll,kf_pitch
+            let ahrs = &state.ahrs;
+            let floats = ahrs.estimator_rp.iter().chain(ahrs.kalman_rp.iter());
+            fill_with_floats(buffer, b"kf", floats);
+        })
+    }
+
+    #[inline]
+    pub fn covariance(
+        &self,
+        state: &types::State,
+        channel: Channel,
+    ) -> Channel {
+        channel.send(|buffer| {
+            // ek:ax,ay,az,bx,by,bz,fx,fy,fz
+            fill_with_floats(buffer, b"ek", state.ahrs.variances.iter());
+        })
+    }
+
+    #[inline]
+    pub fn vibration(&self, state: &types::State, channel: Channel) -> Channel {
+        channel.send(|buffer| {
+            // vb:rms_x,rms_y,rms_z,clipped,accel_trust
+            let results = state.vibration.results();
+            fill_with_floats(buffer, b"vb", results.iter());
+        })
+    }
+
+    #[inline]
+    pub fn autotune(
+        &self,
+        report: &autotune::Report,
+        channel: Channel,
+    ) -> Channel {
+        channel.send(|buffer| {
+            // at:axis,cycles,done,ku,tu,p,i,d
+            fill_with_floats(buffer, b"at", report.results().iter());
+        })
+    }
+
+    #[inline]
+    pub fn mag(
+        &self,
+        calibration: &mag::Calibration,
+        status: &types::CalibrationStatus,
+        channel: Channel,
+    ) -> Channel {
+        channel.send(|buffer| {
+            // mg:bx,by,bz,sx,sy,sz,status
+            let results = calibration.results();
+            let status = [status.code()];
+            let floats = results.iter().chain(status.iter());
+            fill_with_floats(buffer, b"mg", floats);
+        })
+    }
+
+    #[inline]
+    pub fn gyro_temp(
+        &self,
+        model: &thermal::Model,
+        state: &types::State,
+        channel: Channel,
+    ) -> Channel {
+        channel.send(|buffer| {
+            // gt:sx,sy,sz,temp_c,status
+            let results = model.results();
+            let extra = [state.ahrs.temp_c, state.gyro_temp_calibration.code()];
+            let floats = results.iter().chain(extra.iter());
+            fill_with_floats(buffer, b"gt", floats);
+        })
+    }
+
+    #[inline]
+    pub fn imu(
+        &self,
+        config: &imu::Config,
+        state: &types::State,
+        channel: Channel,
+    ) -> Channel {
+        channel.send(|buffer| {
+            // im:gyro_dps,accel_g,dlpf,divisor,sample_hz,loop_hz,rest_status
+            let results = config.results();
+            let measured = [
+                state.ahrs.sample_rate_hz,
+                state.ahrs.loop_hz,
+                state.rest_calibration.code(),
+            ];
+            let floats = results.iter().chain(measured.iter());
+            fill_with_floats(buffer, b"im", floats);
+        })
+    }
+
+    #[inline]
+    pub fn schedule(
+        &self,
+        config: &schedule::Config,
+        state: &types::State,
+        channel: Channel,
+    ) -> Channel {
+        channel.send(|buffer| {
+            // sc:outer_div,telemetry_div,housekeeping_hz,outer_hz,telemetry_hz
+            let results = config.results(state.ahrs.loop_hz);
+            fill_with_floats(buffer, b"sc", results.iter());
+        })
+    }
+
+    #[inline]
+    pub fn perf(&self, perf: &chrono::Perf, channel: Channel) -> Channel {
+        channel.send(|buffer| {
+            // pf:inner_min,inner_avg,inner_max,outer_min,outer_avg,outer_max,
+            //    tm_min,tm_avg,tm_max,hk_min,hk_avg,hk_max,load,
+            //    j2,j5,j10,j20,j50,j100,j200,j_rest
+            fill_with_floats(buffer, b"pf", perf.results().iter());
+        })
+    }
+
+    #[inline]
+    pub fn health(
+        &self,
+        counters: &health::Counters,
+        channel: Channel,
+    ) -> Channel {
+        channel.send(|buffer| {
+            // hl:bus,stuck,saturated,bad_id,reinits,failures,failsafe
+            fill_with_floats(buffer, b"hl", counters.results().iter());
         })
     }
 
@@ -33,17 +248,9 @@ impl Telemetry {
         channel: Channel,
     ) -> Channel {
         channel.send(|buffer| {
-            // ct:pk,ik,dk,pitch_pk,roll_pk,yaw_pk;
-            buffer.push(b'c');
-            buffer.push(b't');
-            buffer.push(b':');
-            for f in control.coefficients().iter() {
-                let mut b = ryu::Buffer::new();
-                let s = b.format(*f);
-                buffer.extend_from_slice(s.as_bytes());
-                buffer.push(b';');
-            }
-            buffer.push(b'\n');
+            // ct:xp,xi,xd,yp,yi,yd,zp,zi,zd,roll_pk,pitch_pk,yaw_pk,
+            //    max_tilt,max_rate,max_corr,i_limit,idle_thrust;
+            fill_with_floats(buffer, b"ct", control.coefficients().iter());
         })
     }
 }
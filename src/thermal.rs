@@ -0,0 +1,112 @@
+// Gyro bias drift with die temperature: linear per sensor axis and
+// relative to temperature at rest calibration. Slopes are fitted while
+// board stays still and warms up.
+use libm::fabsf;
+
+use crate::types::CalibrationError;
+
+// rad/s, faster readings mean board is being moved
+const MAX_REST_RATE: f32 = 0.1;
+// at 250Hz, ~20s
+const MIN_CALIBRATION_SAMPLES: u32 = 5000;
+// degrees Celsius the fit has to span
+const MIN_SPAN_C: f32 = 5.;
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Model {
+    // rad/s per degree Celsius, zero disables compensation
+    pub slopes: [f32; 3],
+}
+
+impl Model {
+    #[inline]
+    pub const fn new() -> Self {
+        Model {
+            slopes: [0.0, 0.0, 0.0],
+        }
+    }
+
+    // change of gyro bias since `reference_c`
+    #[inline]
+    pub fn drift(&self, temp_c: f32, reference_c: f32) -> [f32; 3] {
+        let dt = temp_c - reference_c;
+        [
+            self.slopes[0] * dt,
+            self.slopes[1] * dt,
+            self.slopes[2] * dt,
+        ]
+    }
+
+    // sx,sy,sz
+    #[inline]
+    pub fn results(&self) -> [f32; 3] {
+        self.slopes
+    }
+}
+
+// Least squares line of gyro against temperature per axis, updated
+// sample by sample, so nothing has to be stored.
+pub struct Calibrator {
+    samples: u32,
+    mean_c: f32,
+    mean_gyro: [f32; 3],
+    // sums of squared temperature deviations and of their products
+    // with gyro deviations
+    var_c: f32,
+    cov: [f32; 3],
+    min_c: f32,
+    max_c: f32,
+}
+
+impl Calibrator {
+    #[inline]
+    pub const fn new() -> Self {
+        Calibrator {
+            samples: 0,
+            mean_c: 0.0,
+            mean_gyro: [0.0; 3],
+            var_c: 0.0,
+            cov: [0.0; 3],
+            min_c: 0.0,
+            max_c: 0.0,
+        }
+    }
+
+    // raw gyro, readings in motion are skipped
+    pub fn add(&mut self, gyro: &[f32; 3], temp_c: f32) {
+        if gyro.iter().any(|g| fabsf(*g) > MAX_REST_RATE) {
+            return;
+        }
+        if self.samples == 0 {
+            self.min_c = temp_c;
+            self.max_c = temp_c;
+        } else {
+            self.min_c = self.min_c.min(temp_c);
+            self.max_c = self.max_c.max(temp_c);
+        }
+        self.samples += 1;
+        let n = self.samples as f32;
+        let dc = temp_c - self.mean_c;
+        self.mean_c += dc / n;
+        for i in 0..3 {
+            self.mean_gyro[i] += (gyro[i] - self.mean_gyro[i]) / n;
+            self.cov[i] += dc * (gyro[i] - self.mean_gyro[i]);
+        }
+        self.var_c += dc * (temp_c - self.mean_c);
+    }
+
+    pub fn finish(&self) -> Result<Model, CalibrationError> {
+        if self.samples < MIN_CALIBRATION_SAMPLES {
+            return Err(CalibrationError::TooFewSamples);
+        }
+        // temperature barely changed
+        if self.max_c - self.min_c < MIN_SPAN_C || self.var_c <= 0. {
+            return Err(CalibrationError::PoorCoverage);
+        }
+        let mut slopes = [0.0; 3];
+        for i in 0..3 {
+            slopes[i] = self.cov[i] / self.var_c;
+        }
+        Ok(Model { slopes })
+    }
+}
@@ -1,11 +1,38 @@
 use crate::ahrs::AhrsResult;
+use crate::alignment::Alignment;
+use crate::autotune;
+use crate::dyn_notch;
+use crate::estimators;
+use crate::filters;
+use crate::health;
+use crate::imu;
+use crate::mag;
 use crate::prelude::*;
+use crate::schedule;
+use crate::thermal;
+use crate::vibration;
 
 #[derive(Copy, Clone)]
 pub struct State {
     pub ahrs: AhrsResult,
     pub cmd: [f32; 3],
     pub errors: [f32; 3],
+    pub saturated: bool,
+    // body rate setpoint from outer loop, rad/s
+    pub setpoint: [f32; 3],
+    // active throttle PID attenuation multiplier
+    pub tpa: f32,
+    pub autotune: autotune::Report,
+    // dynamic notch peaks per axis, Hz
+    pub notch_hz: [[f32; dyn_notch::MAX_PEAKS]; 3],
+    pub health: health::Counters,
+    pub vibration: vibration::Levels,
+    // inner loop runs, with or without samples, wrapping
+    pub runs: u32,
+    pub mag_calibration: CalibrationStatus,
+    pub gyro_temp_calibration: CalibrationStatus,
+    // accel and gyro biases taken at rest
+    pub rest_calibration: CalibrationStatus,
 }
 
 impl State {
@@ -15,6 +42,195 @@ impl State {
             ahrs: AhrsResult::new(),
             cmd: [0.0, 0.0, 0.0],
             errors: [0.0, 0.0, 0.0],
+            saturated: false,
+            setpoint: [0.0, 0.0, 0.0],
+            tpa: 1.0,
+            autotune: autotune::Report::new(),
+            notch_hz: [[0.0; dyn_notch::MAX_PEAKS]; 3],
+            health: health::Counters::new(),
+            vibration: vibration::Levels::new(),
+            runs: 0,
+            mag_calibration: CalibrationStatus::None,
+            gyro_temp_calibration: CalibrationStatus::None,
+            rest_calibration: CalibrationStatus::None,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Gains {
+    pub p: f32,
+    pub i: f32,
+    pub d: f32,
+}
+
+impl Gains {
+    #[inline]
+    pub const fn new() -> Self {
+        Gains {
+            p: 0.0,
+            i: 0.0,
+            d: 0.0,
+        }
+    }
+}
+
+pub const TPA_POINTS: usize = 8;
+
+// Throttle PID attenuation: P and D multiplier by thrust.
+// Table, when not empty, takes over breakpoint and rate.
+#[derive(Copy, Clone)]
+pub struct Tpa {
+    pub breakpoint: f32,
+    // attenuation at `max_thrust`, [0, 1]
+    pub rate: f32,
+    pub max_thrust: f32,
+    // (thrust, multiplier), ascending by thrust
+    pub table: [(f32, f32); TPA_POINTS],
+    pub points: usize,
+}
+
+impl Tpa {
+    #[inline]
+    pub const fn new() -> Self {
+        Tpa {
+            breakpoint: 1000.0,
+            rate: 0.0,
+            max_thrust: 2000.0,
+            table: [(0.0, 1.0); TPA_POINTS],
+            points: 0,
+        }
+    }
+
+    // Replaces a point or appends one after the last; thrust has to
+    // stay ascending, otherwise point is ignored.
+    pub fn set_point(&mut self, point: &TpaPoint) {
+        let i = point.index;
+        if i >= TPA_POINTS || i > self.points {
+            return;
+        }
+        let after_previous = i == 0 || self.table[i - 1].0 < point.thrust;
+        let before_next =
+            i + 1 >= self.points || point.thrust < self.table[i + 1].0;
+        if !(after_previous && before_next) {
+            return;
+        }
+        self.table[i] = (point.thrust, point.multiplier);
+        if i == self.points {
+            self.points += 1;
+        }
+    }
+}
+
+// "index,thrust,percents", percents are clamped to [0, 100]
+pub struct TpaPoint {
+    pub index: usize,
+    pub thrust: f32,
+    pub multiplier: f32,
+}
+
+impl core::str::FromStr for TpaPoint {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let mut parts = s.split(',');
+        let mut next = || parts.next().ok_or(());
+        let index = next()?.parse::<usize>().map_err(|_| ())?;
+        let thrust = next()?.parse::<i32>().map_err(|_| ())?;
+        let percents = next()?.parse::<i32>().map_err(|_| ())?;
+        Ok(TpaPoint {
+            index,
+            thrust: thrust as f32,
+            multiplier: crate::utils::clamp(percents as f32 / 100., 0., 1.),
+        })
+    }
+}
+
+// stick feel of a single axis
+#[derive(Copy, Clone)]
+pub struct Rates {
+    pub rc_rate: f32,
+    pub super_rate: f32,
+    pub expo: f32,
+}
+
+impl Rates {
+    #[inline]
+    pub const fn new() -> Self {
+        Rates {
+            rc_rate: 1.0,
+            super_rate: 0.0,
+            expo: 0.0,
+        }
+    }
+}
+
+pub const RATE_PROFILES: usize = 3;
+
+// roll, pitch, yaw
+#[derive(Copy, Clone)]
+pub struct RateProfile {
+    pub axes: [Rates; 3],
+}
+
+impl RateProfile {
+    #[inline]
+    pub const fn new() -> Self {
+        RateProfile {
+            axes: [Rates::new(), Rates::new(), Rates::new()],
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum YawMode {
+    // hold `target_degrees.yaw`, taken over by yaw stick while it turns
+    Heading,
+    // follow `yaw_rate_degrees`
+    Rate,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum FlightMode {
+    // sticks are body rates
+    Acro,
+    // sticks are angles, self-leveling
+    Angle,
+    // angle near center sticks, acro towards full deflection
+    Horizon,
+}
+
+impl FlightMode {
+    // three position switch on aux channel
+    #[inline]
+    pub fn from_aux(aux: f32) -> Self {
+        if aux < -0.33 {
+            FlightMode::Acro
+        } else if aux > 0.33 {
+            FlightMode::Horizon
+        } else {
+            FlightMode::Angle
+        }
+    }
+}
+
+// normalized RC input, [-1, 1]
+#[derive(Copy, Clone)]
+pub struct Sticks {
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+    pub aux: f32,
+}
+
+impl Sticks {
+    #[inline]
+    pub const fn new() -> Self {
+        Sticks {
+            roll: 0.0,
+            pitch: 0.0,
+            yaw: 0.0,
+            aux: 0.0,
         }
     }
 }
@@ -23,13 +239,52 @@ impl State {
 pub struct Control {
     // permanent part
     pub telemetry: bool,
-    pub pk: f32,
-    pub ik: f32,
-    pub dk: f32,
-    pub pitch_pk: f32,
-    pub roll_pk: f32,
-    pub yaw_pk: f32,
+    // rates of tasks other than inner loop
+    pub schedule: schedule::Config,
+    pub mode: FlightMode,
+    // inner (rate) loop gains per body axis: x (roll), y (pitch), z (yaw)
+    pub rate: [Gains; 3],
+    // outer (angle) loop P gains: roll, pitch, yaw
+    pub angle: [f32; 3],
+    // outer loop limits
+    pub max_tilt_degrees: f32,
+    // deg/s
+    pub max_rate_degrees: f32,
+    // inner loop limits, in duty units
+    pub max_correction: f32,
+    // max I contribution
+    pub i_limit: f32,
+    pub imu: imu::Config,
+    pub health: health::Config,
+    // failsafe latch is cleared on next step
+    pub clear_failsafe: bool,
+    pub filters: filters::Settings,
+    // sensor to body rotation, board default
+    pub alignment: Alignment,
+    pub estimator: estimators::Config,
+    // accel trust of estimator by vibration level
+    pub vibration: vibration::Config,
+    pub tpa: Tpa,
+    pub autotune: autotune::Config,
+    // relay experiment starts over on next step
+    pub autotune_restart: bool,
+    pub yaw_mode: YawMode,
+    // deg/s, used in `YawMode::Rate`
+    pub yaw_rate_degrees: f32,
+    // integral is held at zero at or below this thrust
+    pub idle_thrust: f32,
     pub thrust: f32,
+    pub sticks: Sticks,
+    pub rate_profiles: [RateProfile; RATE_PROFILES],
+    pub rate_profile: usize,
+    pub mag: mag::Calibration,
+    // magnetometer calibration routine is collecting samples
+    pub mag_calibrating: bool,
+    pub gyro_temp: thermal::Model,
+    // gyro temperature calibration is collecting samples
+    pub gyro_temp_calibrating: bool,
+    // biases are being taken at rest again, cleared when done
+    pub rest_calibrating: bool,
     // temp, as this has to be controlled from top level ctrl
     // XXX: units via naming? foooo...
     pub target_degrees: EulerAngles,
@@ -40,13 +295,36 @@ impl Control {
     pub const fn new() -> Self {
         Control {
             telemetry: false,
-            pk: 0.0,
-            ik: 0.0,
-            dk: 0.0,
-            pitch_pk: 0.0,
-            roll_pk: 0.0,
-            yaw_pk: 0.0,
+            schedule: schedule::Config::new(),
+            mode: FlightMode::Angle,
+            rate: [Gains::new(), Gains::new(), Gains::new()],
+            angle: [0.0, 0.0, 0.0],
+            max_tilt_degrees: 30.0,
+            max_rate_degrees: 200.0,
+            max_correction: 500.0,
+            i_limit: 100.0,
+            imu: imu::Config::new(),
+            health: health::Config::new(),
+            clear_failsafe: false,
+            filters: filters::Settings::new(),
+            alignment: crate::boards::ALIGNMENT,
+            estimator: estimators::Config::new(),
+            vibration: vibration::Config::new(),
+            tpa: Tpa::new(),
+            autotune: autotune::Config::new(),
+            autotune_restart: false,
+            yaw_mode: YawMode::Heading,
+            yaw_rate_degrees: 0.0,
+            idle_thrust: 0.0,
             thrust: 0.0,
+            sticks: Sticks::new(),
+            rate_profiles: [RateProfile::new(); RATE_PROFILES],
+            rate_profile: 0,
+            mag: mag::Calibration::new(),
+            mag_calibrating: false,
+            gyro_temp: thermal::Model::new(),
+            gyro_temp_calibrating: false,
+            rest_calibrating: false,
             target_degrees: EulerAngles {
                 yaw: 0.0,
                 pitch: 0.0,
@@ -56,20 +334,103 @@ impl Control {
     }
 
     #[inline]
-    pub fn coefficients(&self) -> [f32; 6] {
+    pub fn active_rates(&self) -> &RateProfile {
+        &self.rate_profiles[self.rate_profile]
+    }
+
+    #[inline]
+    pub fn active_rates_mut(&mut self) -> &mut RateProfile {
+        &mut self.rate_profiles[self.rate_profile]
+    }
+
+    #[inline]
+    pub fn set_aux(&mut self, aux: f32) {
+        self.sticks.aux = aux;
+        self.mode = FlightMode::from_aux(aux);
+    }
+
+    #[inline]
+    pub fn coefficients(&self) -> [f32; 17] {
         [
-            self.pk,
-            self.ik,
-            self.dk,
-            self.pitch_pk,
-            self.roll_pk,
-            self.yaw_pk,
+            self.rate[0].p,
+            self.rate[0].i,
+            self.rate[0].d,
+            self.rate[1].p,
+            self.rate[1].i,
+            self.rate[1].d,
+            self.rate[2].p,
+            self.rate[2].i,
+            self.rate[2].d,
+            self.angle[0],
+            self.angle[1],
+            self.angle[2],
+            self.max_tilt_degrees,
+            self.max_rate_degrees,
+            self.max_correction,
+            self.i_limit,
+            self.idle_thrust,
         ]
     }
 }
 
+// why finished calibration run was rejected
+#[derive(Copy, Clone, PartialEq)]
+pub enum CalibrationError {
+    TooFewSamples,
+    // orientations or temperatures didn't vary enough
+    PoorCoverage,
+    // board wasn't at rest
+    Moved,
+    // accel doesn't see 1g at rest
+    BadGravity,
+    // gravity isn't along one axis, board isn't level
+    Tilted,
+}
+
+// outcome of last finished calibration run
+#[derive(Copy, Clone, PartialEq)]
+pub enum CalibrationStatus {
+    None,
+    Accepted,
+    Rejected(CalibrationError),
+}
+
+impl CalibrationStatus {
+    #[inline]
+    pub fn from_result<T>(result: &Result<T, CalibrationError>) -> Self {
+        match result {
+            Ok(_) => CalibrationStatus::Accepted,
+            Err(e) => CalibrationStatus::Rejected(*e),
+        }
+    }
+
+    // 0 - none yet, 1 - accepted, 2 - too few samples, 3 - poor coverage,
+    // 4 - moved, 5 - bad gravity, 6 - tilted
+    #[inline]
+    pub fn code(&self) -> f32 {
+        match self {
+            CalibrationStatus::None => 0.,
+            CalibrationStatus::Accepted => 1.,
+            CalibrationStatus::Rejected(CalibrationError::TooFewSamples) => 2.,
+            CalibrationStatus::Rejected(CalibrationError::PoorCoverage) => 3.,
+            CalibrationStatus::Rejected(CalibrationError::Moved) => 4.,
+            CalibrationStatus::Rejected(CalibrationError::BadGravity) => 5.,
+            CalibrationStatus::Rejected(CalibrationError::Tilted) => 6.,
+        }
+    }
+}
+
 pub enum Requests {
     Status,
+    Autotune,
+    AutotuneAccept,
+    Mag,
+    Imu,
+    Health,
+    GyroTemp,
+    Schedule,
+    Perf,
+    PerfReset,
     Reset,
     Boot,
 }
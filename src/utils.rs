@@ -12,3 +12,28 @@ pub fn fill_with_str(buffer: &mut TxBuffer, arg: &str) {
 pub fn to_rads(d: f32) -> f32 {
     d * PI / 180.
 }
+
+// wraps angle into [-PI, PI)
+pub fn wrap_pi(a: f32) -> f32 {
+    let mut a = a;
+    while a >= PI {
+        a -= 2. * PI;
+    }
+    while a < -PI {
+        a += 2. * PI;
+    }
+    a
+}
+
+#[inline]
+pub fn clamp<T: PartialOrd>(val: T, min: T, max: T) -> T {
+    if val > min {
+        if val < max {
+            val
+        } else {
+            max
+        }
+    } else {
+        min
+    }
+}
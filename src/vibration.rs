@@ -0,0 +1,145 @@
+// Frame vibration: RMS of high-passed accel per body axis and count of
+// accel samples clipped at full scale. Strong vibration lowers how much
+// estimators trust accel.
+use libm::{fabsf, sqrtf};
+
+use crate::filters::{self, Filter, Pt1};
+use crate::imu;
+use crate::utils::clamp;
+
+// below is body motion, above is vibration
+const HIGH_PASS_HZ: f32 = 20.;
+// smoothing of squared levels
+const RMS_HZ: f32 = 2.;
+// accel is never ignored completely, or attitude drifts away
+const MIN_TRUST: f32 = 0.1;
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Config {
+    // total RMS, m/s^2: full trust up to `low`, least from `high` on
+    pub low: f32,
+    pub high: f32,
+}
+
+impl Config {
+    #[inline]
+    pub const fn new() -> Self {
+        Config {
+            low: 3.0,
+            high: 10.0,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Levels {
+    // m/s^2 per body axis
+    pub rms: [f32; 3],
+    // since start
+    pub clipped: u32,
+    // passed to estimator
+    pub accel_trust: f32,
+}
+
+impl Levels {
+    #[inline]
+    pub const fn new() -> Self {
+        Levels {
+            rms: [0.0; 3],
+            clipped: 0,
+            accel_trust: 1.0,
+        }
+    }
+
+    // rms_x,rms_y,rms_z,clipped,accel_trust
+    #[inline]
+    pub fn results(&self) -> [f32; 5] {
+        [
+            self.rms[0],
+            self.rms[1],
+            self.rms[2],
+            self.clipped as f32,
+            self.accel_trust,
+        ]
+    }
+}
+
+pub struct Meter {
+    config: Config,
+    // m/s^2
+    accel_limit: f32,
+    // motion part, subtracted
+    motion: filters::Vector<Pt1>,
+    mean_square: filters::Vector<Pt1>,
+    levels: Levels,
+}
+
+impl Meter {
+    #[inline]
+    pub fn new(imu_config: &imu::Config) -> Self {
+        Meter {
+            config: Config::new(),
+            accel_limit: imu::CLIPPED * imu_config.accel_full_scale(),
+            motion: filters::Vector::new(Pt1::new(), Pt1::new(), Pt1::new()),
+            mean_square: filters::Vector::new(
+                Pt1::new(),
+                Pt1::new(),
+                Pt1::new(),
+            ),
+            levels: Levels::new(),
+        }
+    }
+
+    #[inline]
+    pub fn configure(&mut self, config: &Config) {
+        self.config = *config;
+    }
+
+    pub fn configure_rate(&mut self, sample_hz: f32) {
+        for f in self.motion.axes.iter_mut() {
+            f.configure(HIGH_PASS_HZ, sample_hz);
+        }
+        for f in self.mean_square.axes.iter_mut() {
+            f.configure(RMS_HZ, sample_hz);
+        }
+    }
+
+    #[inline]
+    pub fn configure_range(&mut self, imu_config: &imu::Config) {
+        self.accel_limit = imu::CLIPPED * imu_config.accel_full_scale();
+    }
+
+    // `raw` is sensor accel for clipping, `accel` is in body frame;
+    // returns accel trust.
+    pub fn update(&mut self, raw: &[f32; 3], accel: &[f32; 3]) -> f32 {
+        let limit = self.accel_limit;
+        let levels = &mut self.levels;
+        if raw.iter().any(|a| fabsf(*a) >= limit) {
+            levels.clipped = levels.clipped.wrapping_add(1);
+        }
+        let motion = self.motion.apply(accel);
+        let mut total = 0.;
+        for i in 0..3 {
+            let v = accel[i] - motion[i];
+            let ms = self.mean_square.axes[i].apply(v * v);
+            levels.rms[i] = sqrtf(ms);
+            total += ms;
+        }
+        let rms = sqrtf(total);
+        let span = self.config.high - self.config.low;
+        let excess = if span > 0. {
+            clamp((rms - self.config.low) / span, 0., 1.)
+        } else if rms > self.config.low {
+            1.
+        } else {
+            0.
+        };
+        levels.accel_trust = 1. - excess * (1. - MIN_TRUST);
+        levels.accel_trust
+    }
+
+    #[inline]
+    pub fn levels(&self) -> Levels {
+        self.levels
+    }
+}